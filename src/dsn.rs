@@ -0,0 +1,285 @@
+use crate::envelope::Parameters;
+use crate::mail_sink::DeliveryError;
+use crate::reply::Reply;
+use crate::smtp_server::{Addr, MailData};
+use chrono::Local;
+
+const MAX_ENVID_LEN: usize = 100;
+
+/// Final outcome of a spooled message, as reported in a DSN.
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    /// Handed to Lark, which does not report delivery any further (RFC 3461 "relayed").
+    Relayed,
+    /// Still queued for longer than expected after transient failures, and being retried.
+    Delayed(DeliveryError),
+    Failed(DeliveryError),
+}
+
+/// Decodes RFC 3461 xtext (`+XX` hex escapes).
+pub fn xtext_decode(text: &str) -> String {
+    let mut decoded = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'+' {
+            let hex = [bytes.next().unwrap_or(b'0'), bytes.next().unwrap_or(b'0')];
+            let hex = String::from_utf8_lossy(&hex).to_string();
+            decoded.push(u8::from_str_radix(&hex, 16).unwrap_or(b'?'));
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Decoded xtext for a DSN header line, without the control characters that could end it early.
+fn header_text(text: &str) -> String {
+    xtext_decode(text)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
+
+fn is_xtext(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                let hex = bytes.get(i + 1..i + 3);
+                if !hex.is_some_and(|x| {
+                    x.iter()
+                        .all(|c| c.is_ascii_digit() || (b'A'..=b'F').contains(c))
+                }) {
+                    return false;
+                }
+                i += 3;
+            }
+            b'!'..=b'~' if bytes[i] != b'=' => i += 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn bad_parameter(text: &str) -> Reply {
+    Reply::new(501, "5.5.4", text)
+}
+
+/// Validates the RFC 3461 `RET` and `ENVID` parameters of `MAIL FROM`.
+pub fn check_mail_params(params: &Parameters) -> Result<(), Reply> {
+    if params.contains("RET") {
+        match params.get("RET").map(|x| x.to_uppercase()).as_deref() {
+            Some("FULL") | Some("HDRS") => {}
+            _ => return Err(bad_parameter("RET must be FULL or HDRS")),
+        }
+    }
+    if params.contains("ENVID") {
+        match params.get("ENVID") {
+            Some(envid)
+                if envid.len() <= MAX_ENVID_LEN
+                    && is_xtext(envid)
+                    && header_text(envid) == xtext_decode(envid) => {}
+            _ => return Err(bad_parameter("Invalid ENVID parameter")),
+        }
+    }
+    Ok(())
+}
+
+/// Validates the RFC 3461 `NOTIFY` and `ORCPT` parameters of `RCPT TO`.
+pub fn check_rcpt_params(params: &Parameters) -> Result<(), Reply> {
+    if params.contains("NOTIFY") {
+        let notify = params.get("NOTIFY").unwrap_or("").to_uppercase();
+        let values = notify.split(',').collect::<Vec<_>>();
+        let valid = values == ["NEVER"]
+            || values
+                .iter()
+                .all(|x| ["SUCCESS", "FAILURE", "DELAY"].contains(x));
+        if !valid {
+            return Err(bad_parameter("Invalid NOTIFY parameter"));
+        }
+    }
+    if params.contains("ORCPT") {
+        let valid = params
+            .get("ORCPT")
+            .and_then(|x| x.split_once(';'))
+            .is_some_and(|(kind, address)| {
+                !kind.is_empty()
+                    && kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    && !address.is_empty()
+                    && is_xtext(address)
+                    && header_text(address) == xtext_decode(address)
+            });
+        if !valid {
+            return Err(bad_parameter("Invalid ORCPT parameter"));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a recipient asked to hear about this outcome. Without `NOTIFY` only failures are reported.
+fn wants(params: &Parameters, outcome: &Outcome) -> bool {
+    let notify = params.get("NOTIFY").unwrap_or("FAILURE").to_uppercase();
+    let event = match outcome {
+        Outcome::Relayed => "SUCCESS",
+        Outcome::Delayed(_) => "DELAY",
+        Outcome::Failed(_) => "FAILURE",
+    };
+    notify.split(',').any(|x| x == event)
+}
+
+/// The header section of a message, including the blank line that ends it.
+fn headers(body: &[u8]) -> &[u8] {
+    let end = body
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .map(|x| x + 4)
+        .or_else(|| body.windows(2).position(|x| x == b"\n\n").map(|x| x + 2));
+    &body[..end.unwrap_or(body.len())]
+}
+
+/// Builds an RFC 3464 multipart/report for `mail_data`, addressed to its envelope sender.
+///
+/// Returns `None` when nobody should be notified: the reverse-path is null, or no
+/// recipient asked for this kind of outcome. The report is sent from the original
+/// sender's mailbox with its stored token, never a session token that may be what failed.
+pub fn report(reporting_mta: &str, mail_data: &MailData, outcome: &Outcome) -> Option<MailData> {
+    if mail_data.from.mail_address.is_empty() {
+        return None;
+    }
+    let no_params = Parameters::default();
+    let recipients = mail_data
+        .to
+        .iter()
+        .enumerate()
+        .map(|(i, to)| (to, mail_data.rcpt_params.get(i).unwrap_or(&no_params)))
+        .filter(|(_, params)| wants(params, outcome))
+        .collect::<Vec<_>>();
+    if recipients.is_empty() {
+        return None;
+    }
+
+    let (subject, action, status, diagnostic) = match outcome {
+        Outcome::Relayed => (
+            "Delivery Status Notification (Relayed)",
+            "relayed",
+            "2.0.0".to_string(),
+            None,
+        ),
+        Outcome::Delayed(e) => (
+            "Delivery Status Notification (Delay)",
+            "delayed",
+            e.enhanced.clone(),
+            Some(e.to_string()),
+        ),
+        Outcome::Failed(e) => (
+            "Delivery Status Notification (Failure)",
+            "failed",
+            e.enhanced.clone(),
+            Some(e.to_string()),
+        ),
+    };
+    let addresses = recipients
+        .iter()
+        .map(|(to, _)| to.mail_address.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let boundary = format!("=_dsn_{}", Local::now().format("%Y%m%d%H%M%S%f"));
+
+    let mut text = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{host}>\r\n\
+         To: <{to}>\r\n\
+         Subject: {subject}\r\n\
+         Date: {date}\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n",
+        host = reporting_mta,
+        to = mail_data.from.mail_address,
+        date = Local::now().to_rfc2822(),
+    );
+    text += &match (&diagnostic, outcome) {
+        (Some(diagnostic), Outcome::Delayed(_)) => format!(
+            "Your message to {} has not been delivered yet and will be retried.\r\n\r\n{}\r\n",
+            addresses, diagnostic
+        ),
+        (Some(diagnostic), _) => format!(
+            "Your message to {} could not be delivered.\r\n\r\n{}\r\n",
+            addresses, diagnostic
+        ),
+        (None, _) => format!(
+            "Your message to {} was handed to Lark Mail, \
+             which does not report final delivery.\r\n",
+            addresses
+        ),
+    };
+
+    text += &format!(
+        "\r\n--{}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         Content-Disposition: attachment; filename=\"delivery-status.txt\"\r\n\
+         \r\n\
+         Reporting-MTA: dns; {}\r\n",
+        boundary, reporting_mta
+    );
+    if let Some(envid) = mail_data.mail_params.get("ENVID") {
+        text += &format!("Original-Envelope-Id: {}\r\n", header_text(envid));
+    }
+    for (to, params) in &recipients {
+        text += &format!("\r\nFinal-Recipient: rfc822; {}\r\n", to.mail_address);
+        if let Some((kind, address)) = params.get("ORCPT").and_then(|x| x.split_once(';')) {
+            text += &format!("Original-Recipient: {}; {}\r\n", kind, header_text(address));
+        }
+        text += &format!("Action: {}\r\nStatus: {}\r\n", action, status);
+        if let Some(diagnostic) = &diagnostic {
+            text += &format!("Diagnostic-Code: smtp; {}\r\n", diagnostic);
+        }
+    }
+
+    let full = mail_data
+        .mail_params
+        .get("RET")
+        .is_some_and(|x| x.eq_ignore_ascii_case("FULL"));
+    let (content_type, filename, original) = match full {
+        true => ("message/rfc822", "original.eml", &mail_data.body[..]),
+        false => (
+            "text/rfc822-headers",
+            "original-headers.txt",
+            headers(&mail_data.body),
+        ),
+    };
+    text += &format!(
+        "\r\n--{}\r\n\
+         Content-Type: {}\r\n\
+         Content-Disposition: attachment; filename=\"{}\"\r\n",
+        boundary, content_type, filename
+    );
+    if !original.is_ascii() {
+        text += "Content-Transfer-Encoding: 8bit\r\n";
+    }
+    text += "\r\n";
+
+    let mut body = text.into_bytes();
+    body.extend_from_slice(original);
+    if !body.ends_with(b"\n") {
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Some(MailData {
+        from: mail_data.from.clone(),
+        to: vec![Addr {
+            mail_address: mail_data.from.mail_address.clone(),
+            name: String::new(),
+        }],
+        mail_params: Parameters::default(),
+        rcpt_params: Vec::new(),
+        subject: subject.to_string(),
+        body,
+        access_token: None,
+    })
+}
@@ -0,0 +1,280 @@
+use crate::reply::Reply;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const MAX_PATH_LEN: usize = 256;
+const MAX_LOCAL_PART_LEN: usize = 64;
+const MAX_DOMAIN_LEN: usize = 255;
+
+/// ESMTP parameters of a `MAIL FROM` or `RCPT TO` command, keyed by upper-case keyword.
+///
+/// Values are kept exactly as sent; xtext-encoded values such as `ORCPT` are not decoded.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Parameters(BTreeMap<String, Option<String>>);
+
+impl Parameters {
+    /// Whether the keyword was given, with or without a value.
+    pub fn contains(&self, keyword: &str) -> bool {
+        self.0.contains_key(&keyword.to_uppercase())
+    }
+
+    pub fn get(&self, keyword: &str) -> Option<&str> {
+        self.0.get(&keyword.to_uppercase())?.as_deref()
+    }
+
+    pub fn keywords(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(|x| x.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The path and parameters of a `MAIL FROM` or `RCPT TO` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathArg {
+    /// `local@domain`, with any source route dropped; empty for the null reverse-path `<>`.
+    pub address: String,
+    pub params: Parameters,
+}
+
+fn syntax_error(text: &str) -> Reply {
+    Reply::new(501, "5.5.4", text)
+}
+
+fn bad_address(text: &str) -> Reply {
+    Reply::new(501, "5.1.3", text)
+}
+
+/// Parses `MAIL FROM:<reverse-path> [parameters]`; the null reverse-path `<>` is allowed.
+pub fn parse_mail_from(line: &str) -> Result<PathArg, Reply> {
+    let argument = strip_verb(line, "MAIL FROM:")
+        .ok_or_else(|| syntax_error("Syntax: MAIL FROM:<address> [parameters]"))?;
+    parse_argument(argument, true)
+}
+
+/// Parses `RCPT TO:<forward-path> [parameters]`; a bare `<Postmaster>` is allowed.
+pub fn parse_rcpt_to(line: &str) -> Result<PathArg, Reply> {
+    let argument = strip_verb(line, "RCPT TO:")
+        .ok_or_else(|| syntax_error("Syntax: RCPT TO:<address> [parameters]"))?;
+    parse_argument(argument, false)
+}
+
+fn strip_verb<'a>(line: &'a str, verb: &str) -> Option<&'a str> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let head = line.get(..verb.len())?;
+    if !head.eq_ignore_ascii_case(verb) {
+        return None;
+    }
+    // Tolerate the common `MAIL FROM: <address>` spelling.
+    Some(line[verb.len()..].trim_start_matches(' '))
+}
+
+fn parse_argument(argument: &str, reverse: bool) -> Result<PathArg, Reply> {
+    let (path, rest) = split_path(argument)?;
+    let address = parse_path(path, reverse)?;
+    let params = match rest.trim_end() {
+        "" => Parameters::default(),
+        rest if rest.starts_with(' ') => parse_parameters(rest)?,
+        _ => {
+            return Err(syntax_error(
+                "Parameters must follow the path after a space",
+            ))
+        }
+    };
+    Ok(PathArg { address, params })
+}
+
+/// Splits `<...>` off the front of the argument, honouring `>` inside a quoted local part.
+fn split_path(argument: &str) -> Result<(&str, &str), Reply> {
+    if !argument.starts_with('<') {
+        return Err(bad_address("Address must be enclosed in <>"));
+    }
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in argument.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Ok((&argument[1..i], &argument[i + 1..])),
+            _ => {}
+        }
+    }
+    Err(bad_address("Unterminated address"))
+}
+
+fn parse_path(path: &str, reverse: bool) -> Result<String, Reply> {
+    if path.len() + 2 > MAX_PATH_LEN {
+        return Err(bad_address("Path too long"));
+    }
+    if path.is_empty() {
+        return match reverse {
+            true => Ok(String::new()),
+            false => Err(bad_address("Null forward-path is not allowed")),
+        };
+    }
+    if !reverse && path.eq_ignore_ascii_case("postmaster") {
+        return Ok(path.to_string());
+    }
+
+    // RFC 5321 4.1.2: a source route (`@a,@b:`) is accepted and ignored.
+    let mailbox = if path.starts_with('@') {
+        let (route, mailbox) = path
+            .split_once(':')
+            .ok_or_else(|| bad_address("Bad source route"))?;
+        for hop in route.split(',') {
+            let domain = hop
+                .strip_prefix('@')
+                .ok_or_else(|| bad_address("Bad source route"))?;
+            if !is_domain(domain) {
+                return Err(bad_address("Bad source route"));
+            }
+        }
+        mailbox
+    } else {
+        path
+    };
+
+    let (local, domain) = split_mailbox(mailbox)?;
+    if local.len() > MAX_LOCAL_PART_LEN {
+        return Err(bad_address("Local part too long"));
+    }
+    if !(is_dot_string(local) || is_quoted_string(local)) {
+        return Err(bad_address("Bad local part"));
+    }
+    if !(is_domain(domain) || is_address_literal(domain)) {
+        return Err(bad_address("Bad domain"));
+    }
+    Ok(mailbox.to_string())
+}
+
+/// Splits at the `@` that ends the local part, skipping over a quoted local part.
+fn split_mailbox(mailbox: &str) -> Result<(&str, &str), Reply> {
+    let at = if mailbox.starts_with('"') {
+        let mut escaped = false;
+        let mut end = None;
+        for (i, c) in mailbox.char_indices().skip(1) {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    end = Some(i + 1);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        end.filter(|&i| mailbox[i..].starts_with('@'))
+    } else {
+        mailbox.find('@')
+    };
+    match at {
+        Some(at) => Ok((&mailbox[..at], &mailbox[at + 1..])),
+        None => Err(bad_address("Address must be local-part@domain")),
+    }
+}
+
+/// RFC 5322 atext, extended with UTF-8 by RFC 6531.
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+fn is_dot_string(local: &str) -> bool {
+    local
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+fn is_quoted_string(local: &str) -> bool {
+    let inner = match local.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return false,
+    };
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(' '..='~') => {}
+                _ => return false,
+            },
+            '"' => return false,
+            ' '..='~' => {}
+            c if !c.is_ascii() => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn is_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= MAX_DOMAIN_LEN
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii())
+        })
+}
+
+/// `[192.0.2.1]` or a tagged literal such as `[IPv6:2001:db8::1]`.
+fn is_address_literal(domain: &str) -> bool {
+    let inner = match domain.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+        Some(inner) => inner,
+        None => return false,
+    };
+    match inner.split_once(':') {
+        Some((tag, literal)) => {
+            if tag.eq_ignore_ascii_case("IPv6") {
+                literal.parse::<std::net::Ipv6Addr>().is_ok()
+            } else {
+                is_domain(tag)
+                    && !literal.is_empty()
+                    && literal
+                        .chars()
+                        .all(|c| ('!'..='~').contains(&c) && !"[\\]".contains(c))
+            }
+        }
+        None => inner.parse::<std::net::Ipv4Addr>().is_ok(),
+    }
+}
+
+fn parse_parameters(text: &str) -> Result<Parameters, Reply> {
+    let mut params = BTreeMap::new();
+    for param in text.split_whitespace() {
+        let (keyword, value) = match param.split_once('=') {
+            Some((keyword, value)) => (keyword, Some(value)),
+            None => (param, None),
+        };
+        let valid_keyword = keyword
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+            && keyword
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid_keyword {
+            return Err(syntax_error("Bad parameter keyword"));
+        }
+        if let Some(value) = value {
+            let valid_value = !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| (('!'..='~').contains(&c) && c != '=') || !c.is_ascii());
+            if !valid_value {
+                return Err(syntax_error("Bad parameter value"));
+            }
+        }
+        if params
+            .insert(keyword.to_uppercase(), value.map(|x| x.to_string()))
+            .is_some()
+        {
+            return Err(syntax_error("Duplicate parameter"));
+        }
+    }
+    Ok(Parameters(params))
+}
@@ -1,6 +1,10 @@
+use crate::mail_sink::{Delivery, DeliveryError, MailSink};
+use crate::sasl::TokenVerifier;
 use crate::smtp_server::{Addr, MailData};
 use crate::tools::*;
 use anyhow::anyhow;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_trait::async_trait;
 use base64::engine::general_purpose::URL_SAFE;
 use base64::prelude::*;
 use chrono::Local;
@@ -10,8 +14,13 @@ use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 
 struct UserToken {
@@ -19,6 +28,7 @@ struct UserToken {
     refresh_token: String,
     access_token_expires: u64,
     refresh_token_expires: u64,
+    token_path: String,
 }
 
 #[derive(Debug)]
@@ -27,17 +37,39 @@ struct AppToken {
     token_expires: u64,
 }
 
+pub const LARK_API_BASE: &str = "https://open.larksuite.com";
+pub const FEISHU_API_BASE: &str = "https://open.feishu.cn";
+
 #[derive(Clone)]
 pub struct AppInfo {
     pub app_id: String,
     pub app_secret: String,
+    /// Open API origin without a trailing slash, e.g. [`LARK_API_BASE`].
+    pub api_base: String,
+}
+
+/// Resolves the `api_base` setting: `lark` (default), `feishu`, or a custom origin.
+pub fn api_base(value: Option<&str>) -> String {
+    match value {
+        None | Some("") | Some("lark") => LARK_API_BASE.to_string(),
+        Some("feishu") => FEISHU_API_BASE.to_string(),
+        Some(origin) => origin.trim_end_matches('/').to_string(),
+    }
 }
 
 pub struct LarkMail {
     app_info: AppInfo,
     app_token: Arc<RwLock<AppToken>>,
-    user_token: Arc<RwLock<UserToken>>,
-    http_client: Arc<RwLock<ClientWithMiddleware>>,
+    /// User tokens keyed by lowercase mailbox address.
+    mailboxes: HashMap<String, Arc<RwLock<UserToken>>>,
+    /// Token from `data/refresh_token.json`. It sends for the null reverse-path, and for
+    /// every sender when `app_info.json` lists no mailboxes.
+    default_token: Option<Arc<RwLock<UserToken>>>,
+    /// Whether `app_info.json` lists any mailboxes, even ones whose token failed to load.
+    per_mailbox: bool,
+    /// Listed mailboxes whose token failed to load. Their mail is deferred until re-authorized.
+    unloaded: HashSet<String>,
+    http_client: ClientWithMiddleware,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -46,14 +78,109 @@ struct Attachment {
     filename: String,
 }
 
+/// Classified failure of a Lark Open API call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LarkError {
+    /// The app or user token was rejected or has expired.
+    AuthExpired(String),
+    /// The app lacks a scope, or the user has not granted it.
+    PermissionMissing(String),
+    RateLimited(String),
+    InvalidRecipient(String),
+    PayloadTooLarge(String),
+    ServerError(String),
+    /// The message could not be converted into a Lark request.
+    InvalidMessage(String),
+    /// The API could not be reached at all.
+    Unreachable(String),
+    /// Any other non-zero `code` returned by Lark.
+    Rejected(i64, String),
+}
+
+impl LarkError {
+    pub fn from_response(status: u16, json: &Value, context: &str) -> Self {
+        let code = json["code"].as_i64().unwrap_or(-1);
+        let msg = format!(
+            "{}  ({})",
+            json["msg"]
+                .as_str()
+                .or(json["message"].as_str())
+                .unwrap_or("Unable to parse Lark response JSON"),
+            context
+        );
+
+        match code {
+            99991400 => LarkError::RateLimited(msg),
+            99991661 | 99991663 | 99991664 | 99991665 | 99991668 | 99991677 => {
+                LarkError::AuthExpired(msg)
+            }
+            // authen/v1: authorization code not found, expired or issued to another app, and
+            // refresh token invalid, expired, revoked, already used or issued to another app.
+            20003 | 20004 | 20024 | 20026 | 20037 | 20064 | 20073 | 20074 => {
+                LarkError::AuthExpired(msg)
+            }
+            99991401 | 99991672 | 99991679 => LarkError::PermissionMissing(msg),
+            _ if status == 429 => LarkError::RateLimited(msg),
+            _ if status == 413 => LarkError::PayloadTooLarge(msg),
+            _ if status >= 500 => LarkError::ServerError(msg),
+            _ => LarkError::Rejected(code, msg),
+        }
+    }
+
+    fn from_anyhow(e: anyhow::Error) -> Self {
+        match e.downcast::<LarkError>() {
+            Ok(e) => e,
+            Err(e) if e.is::<reqwest_middleware::Error>() || e.is::<reqwest::Error>() => {
+                LarkError::Unreachable(e.to_string())
+            }
+            Err(e) => LarkError::ServerError(e.to_string()),
+        }
+    }
+
+    pub fn smtp_reply(&self) -> DeliveryError {
+        match self {
+            LarkError::AuthExpired(msg) => {
+                DeliveryError::new(451, "4.7.0", msg.clone()).with_token_rejected()
+            }
+            LarkError::PermissionMissing(msg) => DeliveryError::new(550, "5.7.1", msg.clone()),
+            LarkError::RateLimited(msg) => DeliveryError::new(451, "4.4.5", msg.clone()),
+            LarkError::InvalidRecipient(msg) => DeliveryError::new(550, "5.1.1", msg.clone()),
+            LarkError::PayloadTooLarge(msg) => DeliveryError::new(552, "5.3.4", msg.clone()),
+            LarkError::ServerError(msg) => DeliveryError::new(451, "4.3.0", msg.clone()),
+            LarkError::InvalidMessage(msg) => DeliveryError::new(554, "5.6.0", msg.clone()),
+            LarkError::Unreachable(msg) => DeliveryError::new(451, "4.4.1", msg.clone()),
+            LarkError::Rejected(_, msg) => DeliveryError::new(554, "5.0.0", msg.clone()),
+        }
+    }
+}
+
+impl fmt::Display for LarkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LarkError::Rejected(code, msg) => write!(f, "{} (code {})", msg, code),
+            LarkError::AuthExpired(msg)
+            | LarkError::PermissionMissing(msg)
+            | LarkError::RateLimited(msg)
+            | LarkError::InvalidRecipient(msg)
+            | LarkError::PayloadTooLarge(msg)
+            | LarkError::ServerError(msg)
+            | LarkError::InvalidMessage(msg)
+            | LarkError::Unreachable(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LarkError {}
+
 async fn fetch_app_token(
     app_info: &AppInfo,
-    client: Arc<RwLock<ClientWithMiddleware>>,
+    client: ClientWithMiddleware,
 ) -> Result<AppToken, anyhow::Error> {
     let res = client
-        .write()
-        .await
-        .post("https://open.larksuite.com/open-apis/auth/v3/app_access_token/internal")
+        .post(format!(
+            "{}/open-apis/auth/v3/app_access_token/internal",
+            app_info.api_base
+        ))
         .header("Content-Type", "application/json; charset=utf-8")
         .body(format!(
             r#"{{"app_id":"{}","app_secret":"{}"}}"#,
@@ -62,18 +189,11 @@ async fn fetch_app_token(
         .send()
         .await?;
 
+    let status = res.status().as_u16();
     let json: Value = serde_json::from_str(&res.text().await?)?;
 
     if json["code"].as_i64() != Some(0) {
-        return Err(anyhow!(
-            json["msg"]
-                .as_str()
-                .ok_or(anyhow!(
-                    "fetch_app_token: Unable to parse Lark response JSON"
-                ))?
-                .to_string()
-                + "  (fetch_app_token)"
-        ));
+        return Err(LarkError::from_response(status, &json, "fetch_app_token").into());
     }
 
     let now = SystemTime::now()
@@ -93,15 +213,18 @@ async fn fetch_app_token(
 }
 
 async fn fetch_user_token(
+    api_base: &str,
     app_token: &AppToken,
     code: &str,
-    client: Arc<RwLock<ClientWithMiddleware>>,
+    token_path: &str,
+    client: ClientWithMiddleware,
 ) -> Result<UserToken, anyhow::Error> {
     let error_mag = "fetch_user_token: Unable to parse Lark response JSON";
     let res = client
-        .write()
-        .await
-        .post("https://open.larksuite.com/open-apis/authen/v1/oidc/access_token")
+        .post(format!(
+            "{}/open-apis/authen/v1/oidc/access_token",
+            api_base
+        ))
         .header("Content-Type", "application/json; charset=utf-8")
         .header("Authorization", "Bearer ".to_string() + &app_token.token)
         .body(format!(
@@ -111,16 +234,11 @@ async fn fetch_user_token(
         .send()
         .await?;
 
+    let status = res.status().as_u16();
     let json: Value = serde_json::from_str(&res.text().await?)?;
 
     if json["code"].as_i64() != Some(0) {
-        return Err(anyhow!(
-            json["message"]
-                .as_str()
-                .ok_or(anyhow!(error_mag))?
-                .to_string()
-                + "  (fetch_user_token)"
-        ));
+        return Err(LarkError::from_response(status, &json, "fetch_user_token").into());
     }
 
     let now = SystemTime::now()
@@ -129,7 +247,7 @@ async fn fetch_user_token(
         .as_secs();
 
     write_json(
-        "data/refresh_token.json",
+        token_path,
         &serde_json::json!({
             "token" :json["data"]["refresh_token"]
             .as_str()
@@ -159,19 +277,22 @@ async fn fetch_user_token(
             .ok_or(anyhow!(error_mag))?
             + now
             - 20,
+        token_path: token_path.to_string(),
     })
 }
 
 async fn fetch_user_token_refresh(
+    api_base: &str,
     app_token: &AppToken,
     user_token: &UserToken,
-    client: Arc<RwLock<ClientWithMiddleware>>,
+    client: ClientWithMiddleware,
 ) -> Result<UserToken, anyhow::Error> {
     let error_mag = "fetch_user_token_refresh: Unable to parse Lark response JSON";
     let res = client
-        .write()
-        .await
-        .post("https://open.larksuite.com/open-apis/authen/v1/oidc/refresh_access_token")
+        .post(format!(
+            "{}/open-apis/authen/v1/oidc/refresh_access_token",
+            api_base
+        ))
         .header("Content-Type", "application/json; charset=utf-8")
         .header("Authorization", "Bearer ".to_string() + &app_token.token)
         .body(format!(
@@ -181,16 +302,11 @@ async fn fetch_user_token_refresh(
         .send()
         .await?;
 
+    let status = res.status().as_u16();
     let json: Value = serde_json::from_str(&res.text().await?)?;
 
     if json["code"].as_i64() != Some(0) {
-        return Err(anyhow!(
-            json["message"]
-                .as_str()
-                .ok_or(anyhow!(error_mag))?
-                .to_string()
-                + "  (fetch_user_token_refresh)"
-        ));
+        return Err(LarkError::from_response(status, &json, "fetch_user_token_refresh").into());
     }
 
     let now = SystemTime::now()
@@ -199,7 +315,7 @@ async fn fetch_user_token_refresh(
         .as_secs();
 
     write_json(
-        "data/refresh_token.json",
+        &user_token.token_path,
         &serde_json::json!({
             "token" :json["data"]["refresh_token"]
             .as_str()
@@ -233,6 +349,7 @@ async fn fetch_user_token_refresh(
             .ok_or(anyhow!(error_mag))?
             + now
             - 20,
+        token_path: user_token.token_path.clone(),
     })
 }
 
@@ -240,7 +357,7 @@ async fn check_token_expires(
     uesr_token: &mut UserToken,
     app_token: &mut AppToken,
     app_info: &AppInfo,
-    client: Arc<RwLock<ClientWithMiddleware>>,
+    client: ClientWithMiddleware,
 ) -> Result<(), anyhow::Error> {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -253,7 +370,9 @@ async fn check_token_expires(
     }
 
     if uesr_token.access_token_expires < now || uesr_token.refresh_token_expires < now {
-        let new = fetch_user_token_refresh(app_token, uesr_token, client.clone()).await?;
+        let new =
+            fetch_user_token_refresh(&app_info.api_base, app_token, uesr_token, client.clone())
+                .await?;
         *uesr_token = new;
     }
     Ok(())
@@ -263,7 +382,7 @@ async fn timing_update(
     uesr_token: Arc<RwLock<UserToken>>,
     app_token: Arc<RwLock<AppToken>>,
     app_info: AppInfo,
-    client: Arc<RwLock<ClientWithMiddleware>>,
+    client: ClientWithMiddleware,
 ) {
     loop {
         tokio::time::sleep(std::time::Duration::from_secs(3600 * 24)).await;
@@ -271,16 +390,12 @@ async fn timing_update(
             use std::result::Result::Ok;
             let mut user_token = uesr_token.write().await;
             let mut app_token = app_token.write().await;
-            match check_token_expires(&mut *user_token, &mut *app_token, &app_info, client.clone())
+            match check_token_expires(&mut user_token, &mut app_token, &app_info, client.clone())
                 .await
             {
                 Ok(_) => break,
                 Err(e) => {
-                    println!(
-                        "{}  {}",
-                        Local::now().format("%Y/%m/%d %H:%M:%S").to_string(),
-                        e.to_string()
-                    );
+                    println!("{}  {}", Local::now().format("%Y/%m/%d %H:%M:%S"), e);
                     drop(user_token);
                     drop(app_token);
                 }
@@ -330,10 +445,10 @@ fn parser(mail_data: MailData) -> Result<String, anyhow::Error> {
         }),
     });
 
-    if cc.len() != 0 {
+    if !cc.is_empty() {
         json["cc"] = serde_json::to_value(&cc)?;
     }
-    if bcc.len() != 0 {
+    if !bcc.is_empty() {
         json["bcc"] = serde_json::to_value(&bcc)?;
     }
 
@@ -357,8 +472,8 @@ fn parser(mail_data: MailData) -> Result<String, anyhow::Error> {
             }
             let ctype = format!(
                 "{}/{}",
-                content_type.c_type.to_string(),
-                content_type.c_subtype.as_ref().unwrap().to_string()
+                content_type.c_type,
+                content_type.c_subtype.as_ref().unwrap()
             );
             if attachment.content_id().is_none() {
                 continue;
@@ -385,135 +500,451 @@ fn parser(mail_data: MailData) -> Result<String, anyhow::Error> {
         }
     }
 
-    if html.len() != 0 {
+    if !html.is_empty() {
         json["body_html"] = html.into();
     }
 
-    if attachments.len() != 0 {
+    if !attachments.is_empty() {
         json["attachments"] = serde_json::to_value(&attachments)?;
     }
 
     Ok(json.to_string())
 }
 
+async fn load_user_token(
+    api_base: &str,
+    app_token: &AppToken,
+    code: Option<&str>,
+    token_path: &str,
+    client: ClientWithMiddleware,
+) -> Result<UserToken, anyhow::Error> {
+    if let Some(code) = code.filter(|code| !code.is_empty()) {
+        return fetch_user_token(api_base, app_token, code, token_path, client).await;
+    }
+
+    let error_mag = format!(
+        "Unable to parse json from {}, please re-fill the code at app_info.json to get the token.",
+        token_path
+    );
+    let json = read_json(token_path)?;
+    let uesr_token = UserToken {
+        access_token: "".to_string(),
+        refresh_token: json["token"]
+            .as_str()
+            .ok_or(anyhow!(error_mag.clone()))?
+            .to_string(),
+        access_token_expires: 0,
+        refresh_token_expires: json["expires"].as_u64().ok_or(anyhow!(error_mag))?,
+        token_path: token_path.to_string(),
+    };
+    fetch_user_token_refresh(api_base, app_token, &uesr_token, client).await
+}
+
+const APP_INFO_ERROR: &str = "Unable to parse json from app_info.json";
+const REDIRECT_ADDR: &str = "127.0.0.1:11451";
+/// How long `authorize` waits for the browser to come back.
+const AUTHORIZE_TIMEOUT_SECS: u64 = 600;
+
+fn read_app_info(data_dir: &str) -> Result<(AppInfo, Value), anyhow::Error> {
+    let app_info_config = read_json(&format!("{}/app_info.json", data_dir))?;
+    let app_info = AppInfo {
+        app_id: app_info_config["app_id"]
+            .as_str()
+            .ok_or(anyhow!(APP_INFO_ERROR))?
+            .to_string(),
+        app_secret: app_info_config["app_secret"]
+            .as_str()
+            .ok_or(anyhow!(APP_INFO_ERROR))?
+            .to_string(),
+        api_base: api_base(app_info_config["api_base"].as_str()),
+    };
+    Ok((app_info, app_info_config))
+}
+
+fn http_client() -> ClientWithMiddleware {
+    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+    ClientBuilder::new(reqwest::Client::new())
+        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .build()
+}
+
+/// Waits for the browser to be redirected back with `?code=...&state=...`, or with
+/// `?error=...&state=...` when the user declines.
+async fn wait_for_code(listener: &TcpListener, state: &str) -> Result<String, anyhow::Error> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut reader = BufReader::new(&mut stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+        }
+
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let url = reqwest::Url::parse(&format!("http://{}{}", REDIRECT_ADDR, target))?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let state_matches = query
+            .get("state")
+            .is_some_and(|s| bool::from(s.as_bytes().ct_eq(state.as_bytes())));
+        let (status, body, result) = match (query.get("code"), query.get("error")) {
+            (Some(code), _) if state_matches => (
+                "200 OK",
+                "Authorization complete, you can close this page.",
+                Some(Ok(code.clone())),
+            ),
+            (None, Some(error)) if state_matches => (
+                "200 OK",
+                "Authorization was not granted, you can close this page.",
+                Some(Err(anyhow!("authorization was not granted: {}", error))),
+            ),
+            (Some(_), _) | (_, Some(_)) => {
+                ("400 Bad Request", "State mismatch, please retry.", None)
+            }
+            _ => ("404 Not Found", "Not found.", None),
+        };
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await?;
+        if let Some(result) = result {
+            return result;
+        }
+    }
+}
+
+/// Runs the OAuth flow in the browser and stores the resulting refresh token.
+///
+/// Without `mailbox` the token becomes the default one in `data/refresh_token.json`;
+/// otherwise it is saved for that mailbox and the mailbox is added to `app_info.json`.
+pub async fn authorize(mailbox: Option<&str>) -> Result<(), anyhow::Error> {
+    let (app_info, mut app_info_config) = read_app_info("data")?;
+    let client = http_client();
+    // Unguessable, so another page cannot complete the flow with a code of its own.
+    let mut state = [0; 16];
+    OsRng.fill_bytes(&mut state);
+    let state = state
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>();
+
+    let listener = TcpListener::bind(REDIRECT_ADDR).await?;
+    let url = reqwest::Url::parse_with_params(
+        &format!("{}/open-apis/authen/v1/authorize", app_info.api_base),
+        &[
+            ("app_id", app_info.app_id.as_str()),
+            ("redirect_uri", &format!("http://{}", REDIRECT_ADDR)),
+            ("scope", "mail:user_mailbox.message:send"),
+            ("state", &state),
+        ],
+    )?;
+    println!(
+        "Open the following URL in a browser and approve the authorization:\n\n{}\n",
+        url
+    );
+    let code = tokio::time::timeout(
+        std::time::Duration::from_secs(AUTHORIZE_TIMEOUT_SECS),
+        wait_for_code(&listener, &state),
+    )
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "no authorization received within {} seconds",
+            AUTHORIZE_TIMEOUT_SECS
+        )
+    })??;
+
+    let token_path = match mailbox {
+        Some(address) => {
+            std::fs::create_dir_all("data/refresh_token")?;
+            format!("data/refresh_token/{}.json", address.to_lowercase())
+        }
+        None => "data/refresh_token.json".to_string(),
+    };
+    let app_token = fetch_app_token(&app_info, client.clone()).await?;
+    fetch_user_token(&app_info.api_base, &app_token, &code, &token_path, client).await?;
+
+    if let Some(address) = mailbox {
+        let address = address.to_lowercase();
+        if !app_info_config["mailboxes"].is_array() {
+            app_info_config["mailboxes"] = json!([]);
+        }
+        let mailboxes = app_info_config["mailboxes"].as_array_mut().unwrap();
+        if !mailboxes
+            .iter()
+            .any(|x| x["address"].as_str().map(|x| x.to_lowercase()) == Some(address.clone()))
+        {
+            mailboxes.push(json!({ "address": address }));
+            write_json("data/app_info.json", &app_info_config)?;
+        }
+    }
+
+    println!("Authorization succeeded, token saved to {}", token_path);
+    Ok(())
+}
+
 impl LarkMail {
     pub async fn new() -> Result<Self, anyhow::Error> {
-        let error_msg = "Unable to parse json from app_info.json";
-        let app_info_config = read_json("data/app_info.json")?;
-        let app_info = AppInfo {
-            app_id: app_info_config["app_id"]
-                .as_str()
-                .ok_or(anyhow!(error_msg))?
-                .to_string(),
-            app_secret: app_info_config["app_secret"]
-                .as_str()
-                .ok_or(anyhow!(error_msg))?
-                .to_string(),
-        };
+        Self::from_data_dir("data").await
+    }
 
-        let code = app_info_config["code"].as_str();
+    /// Loads `app_info.json` and the refresh tokens from `data_dir` instead of `data/`.
+    pub async fn from_data_dir(data_dir: &str) -> Result<Self, anyhow::Error> {
+        let (app_info, app_info_config) = read_app_info(data_dir)?;
+        let default_token_path = format!("{}/refresh_token.json", data_dir);
+        let mailbox_configs = app_info_config["mailboxes"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
 
-        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-        let client = Arc::new(RwLock::new(
-            ClientBuilder::new(reqwest::Client::new())
-                .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-                .build(),
-        ));
+        let client = http_client();
 
         let app_token = fetch_app_token(&app_info, client.clone()).await?;
 
-        let user_token = if code.is_some() && code.unwrap().len() > 0 {
-            let code = code.unwrap();
-            write_json(
-                "data/app_info.json",
-                &json!({
-                    "app_id": app_info.app_id,
-                    "app_secret": app_info.app_secret,
-                }),
-            )?;
-            fetch_user_token(&app_token, code, client.clone()).await?
+        // Authorization codes are single use, drop them from app_info.json once consumed.
+        let mut app_info_stripped = app_info_config.clone();
+        let mut code_used = false;
+
+        let code = app_info_config["code"]
+            .as_str()
+            .filter(|code| !code.is_empty());
+        let default_token = if code.is_some()
+            || mailbox_configs.is_empty()
+            || std::path::Path::new(&default_token_path).exists()
+        {
+            code_used |= code.is_some();
+            Some(
+                load_user_token(
+                    &app_info.api_base,
+                    &app_token,
+                    code,
+                    &default_token_path,
+                    client.clone(),
+                )
+                .await?,
+            )
         } else {
-            let error_mag = "Unable to parse json from refresh_token.json, please re-fill the code at app_info.json to get the token.";
-            let json = read_json("data/refresh_token.json")?;
-            let mut uesr_token = UserToken {
-                access_token: "".to_string(),
-                refresh_token: "".to_string(),
-                access_token_expires: 0,
-                refresh_token_expires: 0,
-            };
-            uesr_token.refresh_token = json["token"]
-                .as_str()
-                .ok_or(anyhow!(error_mag))?
-                .to_string();
-            uesr_token.refresh_token_expires =
-                json["expires"].as_u64().ok_or(anyhow!(error_mag))?;
-            fetch_user_token_refresh(&app_token, &uesr_token, client.clone()).await?
+            None
         };
 
-        let app_token = Arc::new(RwLock::new(app_token));
-        let user_token = Arc::new(RwLock::new(user_token));
-
-        let app_token_clone = app_token.clone();
-        let user_token_clone = user_token.clone();
-        let app_info_clone = app_info.clone();
-        let http_client_clone = client.clone();
-        tokio::spawn(async move {
-            timing_update(
-                user_token_clone,
-                app_token_clone,
-                app_info_clone,
-                http_client_clone,
+        let mut mailboxes = HashMap::new();
+        let mut unloaded = HashSet::new();
+        for (i, mailbox) in mailbox_configs.iter().enumerate() {
+            let address = mailbox["address"]
+                .as_str()
+                .ok_or(anyhow!(
+                    "{}: mailboxes[{}].address is missing",
+                    APP_INFO_ERROR,
+                    i
+                ))?
+                .to_lowercase();
+            let code = mailbox["code"].as_str().filter(|code| !code.is_empty());
+            code_used |= code.is_some();
+            std::fs::create_dir_all(format!("{}/refresh_token", data_dir))?;
+            let token_path = format!("{}/refresh_token/{}.json", data_dir, address);
+            let user_token = load_user_token(
+                &app_info.api_base,
+                &app_token,
+                code,
+                &token_path,
+                client.clone(),
             )
             .await;
-        });
+            // One mailbox that needs re-authorizing must not keep the others from sending.
+            match user_token {
+                Ok(user_token) => {
+                    mailboxes.insert(address, Arc::new(RwLock::new(user_token)));
+                }
+                Err(e) => {
+                    println!(
+                        "{}  {}: skipped, run `smtp2larkapi authorize {}`: {}",
+                        Local::now().format("%Y/%m/%d %H:%M:%S"),
+                        address,
+                        address,
+                        e
+                    );
+                    unloaded.insert(address);
+                }
+            }
+            if let Some(mailbox) = app_info_stripped["mailboxes"][i].as_object_mut() {
+                mailbox.remove("code");
+            }
+        }
+
+        if code_used {
+            if let Some(app_info_stripped) = app_info_stripped.as_object_mut() {
+                app_info_stripped.remove("code");
+            }
+            write_json(&format!("{}/app_info.json", data_dir), &app_info_stripped)?;
+        }
+
+        let app_token = Arc::new(RwLock::new(app_token));
+        let default_token = default_token.map(|token| Arc::new(RwLock::new(token)));
+
+        for user_token in mailboxes.values().chain(default_token.iter()) {
+            let app_token_clone = app_token.clone();
+            let user_token_clone = user_token.clone();
+            let app_info_clone = app_info.clone();
+            let http_client_clone = client.clone();
+            tokio::spawn(async move {
+                timing_update(
+                    user_token_clone,
+                    app_token_clone,
+                    app_info_clone,
+                    http_client_clone,
+                )
+                .await;
+            });
+        }
 
         Ok(LarkMail {
-            app_info: app_info,
-            user_token: user_token.clone(),
-            app_token: app_token.clone(),
+            app_info,
+            app_token,
+            mailboxes,
+            default_token,
+            per_mailbox: !mailbox_configs.is_empty(),
+            unloaded,
             http_client: client,
         })
     }
 
-    pub async fn send_mail(&mut self, mail_data: MailData) -> Result<(), anyhow::Error> {
-        let user_token = &mut *self.user_token.write().await;
-        let app_token = &mut *self.app_token.write().await;
-        let mail_from = mail_data.from.clone();
-        let json = parser(mail_data)?;
+    fn user_token(&self, address: &str) -> Result<Arc<RwLock<UserToken>>, LarkError> {
+        let address_lower = address.to_lowercase();
+        if let Some(user_token) = self.mailboxes.get(&address_lower) {
+            return Ok(user_token.clone());
+        }
+        if self.unloaded.contains(&address_lower) {
+            return Err(LarkError::AuthExpired(format!(
+                "Lark token for mailbox {} failed to load, run `smtp2larkapi authorize {}` and restart",
+                address, address
+            )));
+        }
+        // Sending another sender's mail with the default token would send it as the default user.
+        match &self.default_token {
+            Some(user_token) if address.is_empty() || !self.per_mailbox => Ok(user_token.clone()),
+            _ => Err(LarkError::PermissionMissing(format!(
+                "No Lark token is authorized for mailbox {}",
+                address
+            ))),
+        }
+    }
 
+    /// A fresh access token stored for `address`, refreshing it first if needed.
+    async fn stored_access_token(&self, address: &str) -> Result<String, anyhow::Error> {
+        let user_token = self.user_token(address)?;
+        let user_token = &mut *user_token.write().await;
+        let app_token = &mut *self.app_token.write().await;
         check_token_expires(
             user_token,
             app_token,
             &self.app_info,
             self.http_client.clone(),
         )
-        .await?;
+        .await
+        .map_err(LarkError::from_anyhow)?;
+        Ok(user_token.access_token.clone())
+    }
+
+    pub async fn send_mail(&self, mail_data: MailData) -> Result<Delivery, anyhow::Error> {
+        let mail_from = mail_data.from.clone();
+        // Sessions authenticated with a Lark token send as that user; their token cannot be refreshed here.
+        let access_token = match &mail_data.access_token {
+            Some(access_token) => access_token.clone(),
+            None => self.stored_access_token(&mail_from.mail_address).await?,
+        };
+        let json = parser(mail_data).map_err(|e| LarkError::InvalidMessage(e.to_string()))?;
+        // A null reverse-path sends from the mailbox the token belongs to.
+        let mailbox = match mail_from.mail_address.as_str() {
+            "" => "me",
+            address => address,
+        };
+
+        // Quoted local parts may hold `/`, `?` or `#`, so the mailbox is escaped as one path segment.
+        let mut url = reqwest::Url::parse(&format!(
+            "{}/open-apis/mail/v1/user_mailboxes",
+            self.app_info.api_base
+        ))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("api_base {} is not a valid URL", self.app_info.api_base))?
+            .push(mailbox)
+            .extend(["messages", "send"]);
 
         let res = self
             .http_client
-            .write()
-            .await
-            .post(format!(
-                "https://open.larksuite.com/open-apis/mail/v1/user_mailboxes/{}/messages/send",
-                mail_from.mail_address
-            ))
+            .post(url)
             .header("Content-Type", "application/json; charset=utf-8")
-            .header(
-                "Authorization",
-                "Bearer ".to_string() + &user_token.access_token,
-            )
+            .header("Authorization", "Bearer ".to_string() + &access_token)
             .body(json)
             .send()
+            .await
+            .map_err(|e| LarkError::Unreachable(e.to_string()))?;
+
+        let status = res.status().as_u16();
+        let text = res
+            .text()
+            .await
+            .map_err(|e| LarkError::Unreachable(e.to_string()))?;
+        let json: Value = serde_json::from_str(&text).map_err(|_| {
+            LarkError::ServerError("send_mail: Unable to parse Lark response JSON".to_string())
+        })?;
+        if json["code"].as_i64() != Some(0) {
+            return Err(LarkError::from_response(status, &json, "send_mail").into());
+        }
+        Ok(Delivery {
+            message_id: json["data"]["message_id"].as_str().map(|id| id.to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl MailSink for LarkMail {
+    async fn deliver(&self, mail_data: MailData) -> Result<Delivery, anyhow::Error> {
+        self.send_mail(mail_data)
+            .await
+            .map_err(|e| match e.downcast::<LarkError>() {
+                Ok(e) => e.smtp_reply().into(),
+                Err(e) => e,
+            })
+    }
+}
+
+#[async_trait]
+impl TokenVerifier for LarkMail {
+    async fn mailbox(&self, access_token: &str) -> Result<Option<String>, anyhow::Error> {
+        let res = self
+            .http_client
+            .get(format!(
+                "{}/open-apis/authen/v1/user_info",
+                self.app_info.api_base
+            ))
+            .header("Authorization", "Bearer ".to_string() + access_token)
+            .send()
             .await?;
 
+        let status = res.status().as_u16();
         let json: Value = serde_json::from_str(&res.text().await?)?;
-        let error_msg = "send_mail: Unable to parse Lark response JSON";
         if json["code"].as_i64() != Some(0) {
-            return Err(anyhow!(json["msg"]
-                .as_str()
-                .ok_or(anyhow!(error_msg))?
-                .to_string()));
+            return match LarkError::from_response(status, &json, "user_info") {
+                LarkError::AuthExpired(_) | LarkError::PermissionMissing(_) => Ok(None),
+                e => Err(e.into()),
+            };
         }
-        Ok(())
+
+        // Tenants with their own mail domain report the mailbox as enterprise_email.
+        let data = &json["data"];
+        Ok([&data["enterprise_email"], &data["email"]]
+            .iter()
+            .filter_map(|x| x.as_str())
+            .find(|x| !x.is_empty())
+            .map(|x| x.to_lowercase()))
     }
 }
@@ -1,3 +1,12 @@
+pub mod dsn;
+pub mod envelope;
 pub mod lark_api_mail;
+pub mod mail_sink;
+pub mod password;
+pub mod reply;
+pub mod sasl;
 pub mod smtp_server;
+pub mod socket;
+pub mod spool;
+pub mod tls;
 pub mod tools;
@@ -0,0 +1,63 @@
+use crate::smtp_server::MailData;
+use async_trait::async_trait;
+use std::fmt;
+
+/// Result of handing a finished message to a [`MailSink`].
+#[derive(Debug, Clone, Default)]
+pub struct Delivery {
+    /// Identifier the backend assigned to the message, if it reports one.
+    pub message_id: Option<String>,
+}
+
+/// Delivery backend that receives every message accepted by the SMTP front-end.
+#[async_trait]
+pub trait MailSink: Send + Sync {
+    async fn deliver(&self, mail_data: MailData) -> Result<Delivery, anyhow::Error>;
+
+    /// Whether outcomes are reported back as RFC 3461 delivery status notifications,
+    /// which decides if DSN is offered to clients.
+    fn reports_status(&self) -> bool {
+        false
+    }
+}
+
+/// Delivery failure carrying the SMTP reply it should be reported to the client as.
+///
+/// Sinks return it inside their `anyhow::Error`; any other error is reported
+/// as a transient local failure.
+#[derive(Debug, Clone)]
+pub struct DeliveryError {
+    pub code: u16,
+    pub enhanced: String,
+    pub message: String,
+    /// The backend refused the access token the message was sent with.
+    pub token_rejected: bool,
+}
+
+impl DeliveryError {
+    pub fn new(code: u16, enhanced: &str, message: impl Into<String>) -> Self {
+        DeliveryError {
+            code,
+            enhanced: enhanced.to_string(),
+            message: message.into(),
+            token_rejected: false,
+        }
+    }
+
+    pub fn with_token_rejected(mut self) -> Self {
+        self.token_rejected = true;
+        self
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.code >= 500
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.code, self.enhanced, self.message)
+    }
+}
+
+impl std::error::Error for DeliveryError {}
@@ -1,106 +1,200 @@
-use chrono::Local;
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use serde::{Deserialize, Serialize};
+use smtp2larkapi::mail_sink::MailSink;
+use smtp2larkapi::sasl::Registry;
+use smtp2larkapi::socket::{self, Socket};
+use smtp2larkapi::spool::Spool;
+use smtp2larkapi::tls::{self, TlsConfig};
 use smtp2larkapi::tools::*;
 use smtp2larkapi::{lark_api_mail, smtp_server::*};
+use std::io::IsTerminal;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 
+/// One address to accept connections on. Unset fields fall back to the top-level ones.
+///
+/// `address` is `host:port`, `unix:<path>` for a Unix socket, or `systemd:<name>` for a
+/// socket passed in by systemd socket activation.
 #[derive(Deserialize, Serialize)]
-struct Tls {
-    cert: String,
-    key: String,
+struct Listener {
+    address: String,
+    safety: Option<String>,
+    tls: Option<TlsConfig>,
+    auth_mechanisms: Option<Vec<String>>,
+    /// Octal permissions of a Unix socket file, e.g. `"660"`.
+    mode: Option<String>,
+    /// Numeric uid and gid a Unix socket file is handed to.
+    owner: Option<u32>,
+    group: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize)]
 struct Config {
-    user: String,
-    passwd: String,
+    user: Option<String>,
+    passwd: Option<String>,
+    accounts: Option<Vec<Account>>,
     default_name: Option<String>,
-    listener: String,
+    listener: Option<String>,
+    listeners: Option<Vec<Listener>>,
     host: String,
-    safety: String,
-    tls: Option<Tls>,
+    safety: Option<String>,
+    tls: Option<TlsConfig>,
+    delivery: Option<String>,
+    max_size: Option<usize>,
+    auth_mechanisms: Option<Vec<String>>,
+}
+
+fn hash_password_command() -> Result<(), anyhow::Error> {
+    let password = if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("Password: ")?;
+        if password != rpassword::prompt_password("Repeat password: ")? {
+            return Err(anyhow::anyhow!("Passwords do not match"));
+        }
+        password
+    } else {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        password.trim_end_matches(['\r', '\n']).to_string()
+    };
+    println!("{}", smtp2larkapi::password::hash_password(&password)?);
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    match std::env::args().nth(1).as_deref() {
+        Some("hash-password") => return hash_password_command(),
+        Some("authorize") => {
+            return lark_api_mail::authorize(std::env::args().nth(2).as_deref()).await
+        }
+        _ => {}
+    }
+
     let config_json = read_json("data/config.json")?;
     let config: Config = serde_json::from_value(config_json)?;
 
-    let listener = tokio::net::TcpListener::bind(&config.listener).await?;
-    println!("Listening on {}", listener.local_addr()?);
-
-    let mut tls_cert = None;
-    if let Some(tls_config) = config.tls {
-        let private_key = match PrivateKeyDer::from_pem_file(&tls_config.key) {
-            Ok(key) => key,
-            Err(e) => panic!("{:?}", e),
-        };
-        let certs: Vec<_> = CertificateDer::pem_file_iter(&tls_config.cert)
-            .unwrap()
-            .map(|cert| cert.unwrap())
-            .collect();
-        tls_cert = Some(Arc::new(
-            rustls::ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(certs, private_key)?,
-        ));
+    let mut accounts = config.accounts.unwrap_or_default();
+    if let (Some(user), Some(passwd)) = (config.user, config.passwd) {
+        accounts.push(Account {
+            user,
+            passwd,
+            ..Default::default()
+        });
     }
+    let listeners = match config.listeners {
+        Some(listeners) if !listeners.is_empty() => listeners,
+        _ => vec![Listener {
+            address: config.listener.clone().ok_or(anyhow::anyhow!(
+                "config.json: either listener or listeners must be set"
+            ))?,
+            safety: None,
+            tls: None,
+            auth_mechanisms: None,
+            mode: None,
+            owner: None,
+            group: None,
+        }],
+    };
 
-    let mail_config = Arc::new(MailConfig {
-        user: config.user.clone(),
-        passwd: config.passwd.clone(),
-        default_name: config.default_name.unwrap_or_default(),
-        tls_cert: tls_cert.clone(),
-        tls_type: match config.safety.as_str() {
+    let lark = Arc::new(lark_api_mail::LarkMail::new().await?);
+    #[cfg(unix)]
+    let mut activated = socket::Activated::from_env()?;
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let safety = listener
+            .safety
+            .or(config.safety.clone())
+            .unwrap_or_else(|| "no".to_string());
+        let tls_type = match safety.as_str() {
             "starttls" => Some(TlsType::STARTTLS),
             "ssl" => Some(TlsType::SSL),
             _ => None,
-        },
-        host: config.host.clone(),
-    });
+        };
+        let mut tls_cert = None;
+        if let Some(tls_config) = listener.tls.or(config.tls.clone()) {
+            let (server_config, resolver) = tls::server_config(tls_config)
+                .map_err(|e| anyhow::anyhow!("config.json: {}: tls: {}", listener.address, e))?;
+            resolver.watch(tls::RELOAD_POLL_INTERVAL);
+            tls_cert = Some(server_config);
+        }
+        if tls_type.is_some() && tls_cert.is_none() {
+            return Err(anyhow::anyhow!(
+                "config.json: {}: safety {} needs tls",
+                listener.address,
+                safety
+            ));
+        }
+        let sasl = match listener
+            .auth_mechanisms
+            .as_ref()
+            .or(config.auth_mechanisms.as_ref())
+        {
+            Some(names) => Registry::builtin(names)
+                .map_err(|e| anyhow::anyhow!("config.json: {}: {}", listener.address, e))?,
+            None => Registry::default(),
+        };
+        let mail_config = Arc::new(MailConfig {
+            accounts: accounts.clone(),
+            default_name: config.default_name.clone().unwrap_or_default(),
+            max_size: config.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            sasl,
+            tokens: Some(lark.clone()),
+            tls_cert,
+            tls_type,
+            host: config.host.clone(),
+        });
+        let socket = match listener.address.split_once(':') {
+            #[cfg(unix)]
+            Some(("unix", path)) => Socket::Unix(socket::bind_unix(
+                path,
+                listener.mode.as_deref(),
+                listener.owner,
+                listener.group,
+            )?),
+            #[cfg(unix)]
+            Some(("systemd", name)) => activated.take(name)?,
+            _ => Socket::Tcp(
+                tokio::net::TcpListener::bind(&listener.address)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{}: {}", listener.address, e))?,
+            ),
+        };
+        // A bound Unix socket reports the path it was staged at before being moved into place.
+        let name = match &socket {
+            #[cfg(unix)]
+            Socket::Unix(_) if !listener.address.starts_with("systemd:") => {
+                listener.address.clone()
+            }
+            _ => socket.describe(),
+        };
+        println!("Listening on {} ({})", name, safety);
+        servers.push((socket, mail_config));
+    }
 
-    let lark = lark_api_mail::LarkMail::new().await?;
-    let lark = Arc::new(RwLock::new(lark));
+    // Token-based mechanisms authenticate Lark users without any configured account.
+    let oauth = servers.iter().any(|(_, mail_config)| {
+        mail_config
+            .sasl
+            .names()
+            .iter()
+            .any(|x| ["XOAUTH2", "OAUTHBEARER"].contains(x))
+    });
+    if accounts.is_empty() && !oauth {
+        return Err(anyhow::anyhow!(
+            "config.json: either user/passwd or accounts must be set"
+        ));
+    }
 
-    loop {
-        let lark = lark.clone();
-        let mail_config = mail_config.clone();
-        let (mut stream, _) = listener.accept().await?;
+    let lark: Arc<dyn MailSink> = lark;
+    let sink: Arc<dyn MailSink> = match config.delivery.as_deref() {
+        Some("sync") => lark,
+        _ => Spool::new("data/spool", &config.host, lark)?,
+    };
 
-        tokio::spawn(async move {
-            let mut mail = Mail::new(&mut stream, mail_config);
-            match mail.run().await {
-                Ok(_) => {
-                    let Mail { mail_data, .. } = mail;
-                    let mail_to = mail_data.to.clone();
-                    println!(
-                        "{}  Received an email request to send: {:?}",
-                        Local::now().format("%Y/%m/%d %H:%M:%S").to_string(),
-                        &mail_to.iter().map(|x| x.mail_address.clone()).collect::<Vec<_>>()
-                    );
-                    match lark.write().await.send_mail(mail_data).await {
-                        Ok(_) => println!(
-                            "{}  to: {:?} send success",
-                            Local::now().format("%Y/%m/%d %H:%M:%S").to_string(),
-                            &mail_to.iter().map(|x| x.mail_address.clone()).collect::<Vec<_>>()
-                        ),
-                        Err(e) => println!(
-                            "{}  to:{:?}  {}",
-                            Local::now().format("%Y/%m/%d %H:%M:%S").to_string(),
-                            &mail_to.iter().map(|x| x.mail_address.clone()).collect::<Vec<_>>(),
-                            e.to_string()
-                        ),
-                    };
-                }
-                Err(e) => println!(
-                    "{} Error: {}",
-                    Local::now().format("%Y/%m/%d %H:%M:%S").to_string().trim_end(),
-                    e
-                ),
-            }
-        });
+    let mut tasks = tokio::task::JoinSet::new();
+    for (socket, mail_config) in servers {
+        tasks.spawn(listen(socket, mail_config, sink.clone()));
+    }
+    while let Some(result) = tasks.join_next().await {
+        result??;
     }
+    Ok(())
 }
@@ -0,0 +1,59 @@
+use anyhow::anyhow;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+
+/// Checks a password against a stored value from `config.json`.
+///
+/// The stored value may be an argon2 (`$argon2id$...`), bcrypt (`$2b$...`) or
+/// SHA-512-crypt (`$6$...`) hash; anything else is treated as a plaintext
+/// password and compared in constant time. An empty stored value never matches,
+/// so accounts that only log in with a client certificate can leave it out.
+pub fn verify_password(stored: &str, password: &str) -> bool {
+    if stored.is_empty() {
+        false
+    } else if stored.starts_with("$argon2") {
+        match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        }
+    } else if stored.starts_with("$2") {
+        bcrypt::verify(password, stored).unwrap_or(false)
+    } else if stored.starts_with("$6$") {
+        sha_crypt::sha512_check(password, stored).is_ok()
+    } else {
+        stored.as_bytes().ct_eq(password.as_bytes()).into()
+    }
+}
+
+/// The stored password itself when it is not one of the hash formats above.
+///
+/// Challenge-response mechanisms such as CRAM-MD5 and SCRAM need the password, not a hash of it.
+pub fn plaintext(stored: &str) -> Option<&str> {
+    let hashed =
+        stored.starts_with("$argon2") || stored.starts_with("$2") || stored.starts_with("$6$");
+    (!hashed && !stored.is_empty()).then_some(stored)
+}
+
+/// An argon2 hash of a random password, checked for unknown users so that a
+/// login attempt costs the same whether or not the user exists.
+pub fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        hash_password(salt.as_str()).unwrap_or_default()
+    })
+}
+
+/// Hashes a password with argon2id and a random salt.
+pub fn hash_password(password: &str) -> Result<String, anyhow::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Unable to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
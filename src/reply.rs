@@ -0,0 +1,69 @@
+use crate::mail_sink::DeliveryError;
+use std::fmt;
+
+/// SMTP reply with a code, an optional RFC 3463 enhanced status code and one or more lines of text.
+///
+/// Handlers return it on success and inside their `anyhow::Error` on failure;
+/// `Display` produces the wire format, CRLF included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    code: u16,
+    enhanced: Option<String>,
+    lines: Vec<String>,
+}
+
+/// Keeps text from a sink or a client from breaking the reply framing.
+fn clean(text: impl Into<String>) -> String {
+    text.into().replace(['\r', '\n'], " ")
+}
+
+impl Reply {
+    pub fn new(code: u16, enhanced: &str, text: impl Into<String>) -> Self {
+        Reply {
+            code,
+            enhanced: Some(enhanced.to_string()),
+            lines: vec![clean(text)],
+        }
+    }
+
+    /// Reply without an enhanced status code, for the greeting, EHLO and intermediate replies.
+    pub fn plain(code: u16, text: impl Into<String>) -> Self {
+        Reply {
+            code,
+            enhanced: None,
+            lines: vec![clean(text)],
+        }
+    }
+
+    /// Appends a line, turning the reply into a multiline one.
+    pub fn line(mut self, text: impl Into<String>) -> Self {
+        self.lines.push(clean(text));
+        self
+    }
+
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            let separator = if i + 1 == self.lines.len() { ' ' } else { '-' };
+            write!(f, "{}{}", self.code, separator)?;
+            if let Some(enhanced) = &self.enhanced {
+                write!(f, "{} ", enhanced)?;
+            }
+            write!(f, "{}\r\n", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Reply {}
+
+impl From<&DeliveryError> for Reply {
+    fn from(e: &DeliveryError) -> Self {
+        Reply::new(e.code, &e.enhanced, e.message.clone())
+    }
+}
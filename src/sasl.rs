@@ -0,0 +1,575 @@
+use crate::password::{dummy_hash, plaintext, verify_password};
+use crate::smtp_server::Account;
+use anyhow::anyhow;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_trait::async_trait;
+use base64::prelude::*;
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+/// OAuth scope reported to clients whose bearer token was rejected.
+const OAUTH_SCOPE: &str = "mail:user_mailbox.message:send";
+
+const SCRAM_ITERATIONS: u32 = 4096;
+
+/// Mechanisms offered when `auth_mechanisms` is not set in `config.json`.
+pub const DEFAULT_MECHANISMS: [&str; 2] = ["LOGIN", "PLAIN"];
+
+/// What the server does after a client response.
+pub enum Step {
+    /// Send this challenge (base64-encoded on the wire) and wait for the next response.
+    Challenge(Vec<u8>),
+    Success(Account),
+    Failure,
+    /// The credentials could not be checked right now; the client may retry later.
+    Unavailable,
+}
+
+/// Maps an OAuth bearer token to the mailbox it belongs to.
+#[async_trait]
+pub trait TokenVerifier: Send + Sync {
+    /// `Ok(None)` when the token is invalid or expired, `Err` when it could not be checked.
+    async fn mailbox(&self, access_token: &str) -> Result<Option<String>, anyhow::Error>;
+}
+
+/// What a mechanism checks credentials against.
+#[derive(Clone, Copy)]
+pub struct Credentials<'a> {
+    pub accounts: &'a [Account],
+    /// Verifies XOAUTH2 and OAUTHBEARER tokens; without it every token is rejected.
+    pub tokens: Option<&'a dyn TokenVerifier>,
+}
+
+/// Server side of one SASL exchange, created fresh for every `AUTH` command.
+#[async_trait]
+pub trait Mechanism: Send + Sync {
+    /// Handles the next decoded client response. `None` means `AUTH` carried no initial response.
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step;
+}
+
+/// Creates a mechanism for a session of the server named `host`.
+pub type Factory = fn(host: &str) -> Box<dyn Mechanism>;
+
+/// The SASL mechanisms a server offers, in the order they are advertised.
+#[derive(Clone)]
+pub struct Registry {
+    mechanisms: Vec<(String, Factory)>,
+}
+
+fn builtin(name: &str) -> Option<Factory> {
+    let factory: Factory = match name {
+        "PLAIN" => |_| Box::new(Plain),
+        "LOGIN" => |_| Box::new(Login { user: None }),
+        "CRAM-MD5" => |host| {
+            Box::new(CramMd5 {
+                host: host.to_string(),
+                challenge: None,
+            })
+        },
+        "SCRAM-SHA-256" => |_| Box::new(ScramSha256::Start),
+        "XOAUTH2" => |_| Box::new(XOAuth2 { rejected: false }),
+        "OAUTHBEARER" => |_| Box::new(OAuthBearer { rejected: false }),
+        _ => return None,
+    };
+    Some(factory)
+}
+
+impl Registry {
+    pub fn empty() -> Self {
+        Registry {
+            mechanisms: Vec::new(),
+        }
+    }
+
+    /// Enables the named built-in mechanisms: PLAIN, LOGIN, CRAM-MD5, SCRAM-SHA-256, XOAUTH2 and OAUTHBEARER.
+    pub fn builtin(names: &[String]) -> Result<Self, anyhow::Error> {
+        let mut registry = Registry::empty();
+        for name in names {
+            let name = name.to_uppercase();
+            let factory =
+                builtin(&name).ok_or_else(|| anyhow!("Unknown SASL mechanism {}", name))?;
+            registry.register(&name, factory);
+        }
+        Ok(registry)
+    }
+
+    /// Adds a mechanism, replacing any registered under the same name.
+    pub fn register(&mut self, name: &str, factory: Factory) {
+        let name = name.to_uppercase();
+        match self.mechanisms.iter_mut().find(|(x, _)| *x == name) {
+            Some(entry) => entry.1 = factory,
+            None => self.mechanisms.push((name, factory)),
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.mechanisms
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    pub fn start(&self, name: &str, host: &str) -> Option<Box<dyn Mechanism>> {
+        let name = name.to_uppercase();
+        let (_, factory) = self.mechanisms.iter().find(|(x, _)| *x == name)?;
+        Some(factory(host))
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let names = DEFAULT_MECHANISMS.map(|x| x.to_string());
+        Registry::builtin(&names).unwrap()
+    }
+}
+
+async fn check_credentials(accounts: &[Account], user: &str, passwd: String) -> Option<Account> {
+    let account = accounts
+        .iter()
+        .find(|account| account.user == user)
+        .cloned();
+    let stored = match &account {
+        Some(account) => account.passwd.clone(),
+        None => dummy_hash().to_string(),
+    };
+    // Hash verification is deliberately slow, keep it off the async workers.
+    let valid = tokio::task::spawn_blocking(move || verify_password(&stored, &passwd))
+        .await
+        .unwrap_or(false);
+    account.filter(|_| valid)
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// RFC 4616.
+struct Plain;
+
+#[async_trait]
+impl Mechanism for Plain {
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step {
+        let response = match response {
+            Some(response) => response,
+            None => return Step::Challenge(Vec::new()),
+        };
+        let fields = response.split(|x| *x == 0).collect::<Vec<_>>();
+        if fields.len() != 3 {
+            return Step::Failure;
+        }
+        let authzid = String::from_utf8_lossy(fields[0]);
+        let user = String::from_utf8_lossy(fields[1]);
+        let passwd = String::from_utf8_lossy(fields[2]).to_string();
+        // Acting on behalf of another account is not supported.
+        if !authzid.is_empty() && authzid != user {
+            return Step::Failure;
+        }
+        match check_credentials(credentials.accounts, &user, passwd).await {
+            Some(account) => Step::Success(account),
+            None => Step::Failure,
+        }
+    }
+}
+
+/// The de facto LOGIN mechanism: username and password prompted one after the other.
+struct Login {
+    user: Option<String>,
+}
+
+#[async_trait]
+impl Mechanism for Login {
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step {
+        let response = match response {
+            Some(response) => String::from_utf8_lossy(&response).to_string(),
+            None => return Step::Challenge(b"Username:".to_vec()),
+        };
+        match self.user.take() {
+            None => {
+                self.user = Some(response);
+                Step::Challenge(b"Password:".to_vec())
+            }
+            Some(user) => match check_credentials(credentials.accounts, &user, response).await {
+                Some(account) => Step::Success(account),
+                None => Step::Failure,
+            },
+        }
+    }
+}
+
+/// RFC 2195. Only works for accounts whose password is stored in plaintext.
+struct CramMd5 {
+    host: String,
+    challenge: Option<Vec<u8>>,
+}
+
+#[async_trait]
+impl Mechanism for CramMd5 {
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step {
+        let (challenge, response) = match (self.challenge.take(), response) {
+            (Some(challenge), Some(response)) => (challenge, response),
+            (None, None) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let random = u64::from_be_bytes(random_bytes(8).try_into().unwrap());
+                let challenge = format!("<{}.{}@{}>", random, now, self.host).into_bytes();
+                self.challenge = Some(challenge.clone());
+                return Step::Challenge(challenge);
+            }
+            // CRAM-MD5 has no initial response.
+            _ => return Step::Failure,
+        };
+
+        let response = String::from_utf8_lossy(&response);
+        let (user, digest) = match response.rsplit_once(' ') {
+            Some(x) => x,
+            None => return Step::Failure,
+        };
+        let account = match credentials
+            .accounts
+            .iter()
+            .find(|account| account.user == user)
+        {
+            Some(account) => account,
+            None => return Step::Failure,
+        };
+        let passwd = match plaintext(&account.passwd) {
+            Some(passwd) => passwd,
+            None => return Step::Failure,
+        };
+        let mut mac = Hmac::<Md5>::new_from_slice(passwd.as_bytes()).unwrap();
+        mac.update(&challenge);
+        let expected = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        match expected
+            .as_bytes()
+            .ct_eq(digest.to_lowercase().as_bytes())
+            .into()
+        {
+            true => Step::Success(account.clone()),
+            false => Step::Failure,
+        }
+    }
+}
+
+/// RFC 5802 / RFC 7677, without channel binding. Only works for accounts whose
+/// password is stored in plaintext; the salt is fresh for every exchange.
+enum ScramSha256 {
+    Start,
+    Challenged {
+        account: Option<Account>,
+        salted_password: Vec<u8>,
+        gs2_header: String,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+    },
+    Verified(Account),
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Undoes the `=2C` / `=3D` escaping of a SCRAM username.
+fn decode_saslname(name: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(i) = rest.find('=') {
+        decoded += &rest[..i];
+        match rest.get(i..i + 3) {
+            Some("=2C") => decoded.push(','),
+            Some("=3D") => decoded.push('='),
+            _ => return None,
+        }
+        rest = &rest[i + 3..];
+    }
+    decoded += rest;
+    Some(decoded)
+}
+
+impl ScramSha256 {
+    fn client_first(message: &str, accounts: &[Account]) -> Option<(ScramSha256, Vec<u8>)> {
+        let (cbind_flag, rest) = message.split_once(',')?;
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return None;
+        }
+        let (authzid, client_first_bare) = rest.split_once(',')?;
+        let gs2_header = format!("{},{},", cbind_flag, authzid);
+
+        let mut attributes = client_first_bare.split(',');
+        let user = decode_saslname(attributes.next()?.strip_prefix("n=")?)?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        if client_nonce.is_empty() || client_nonce.contains(',') {
+            return None;
+        }
+        if let Some(authzid) = authzid.strip_prefix("a=") {
+            if decode_saslname(authzid)? != user {
+                return None;
+            }
+        } else if !authzid.is_empty() {
+            return None;
+        }
+
+        let account = accounts
+            .iter()
+            .find(|account| account.user == user)
+            .cloned();
+        // Unknown users and hashed passwords go through the same motions and fail at the proof.
+        let passwd = match account.as_ref().and_then(|x| plaintext(&x.passwd)) {
+            Some(passwd) => passwd.as_bytes().to_vec(),
+            None => random_bytes(32),
+        };
+        let salt = random_bytes(16);
+        let mut salted_password = vec![0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&passwd, &salt, SCRAM_ITERATIONS, &mut salted_password);
+
+        let nonce = format!(
+            "{}{}",
+            client_nonce,
+            BASE64_STANDARD.encode(random_bytes(18))
+        );
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            BASE64_STANDARD.encode(&salt),
+            SCRAM_ITERATIONS
+        );
+        let challenge = server_first.clone().into_bytes();
+        let state = ScramSha256::Challenged {
+            account: account.filter(|x| plaintext(&x.passwd).is_some()),
+            salted_password,
+            gs2_header,
+            client_first_bare: client_first_bare.to_string(),
+            server_first,
+            nonce,
+        };
+        Some((state, challenge))
+    }
+
+    /// Checks the client proof and returns the account with the server signature.
+    fn client_final(&self, message: &str) -> Option<(Account, Vec<u8>)> {
+        let ScramSha256::Challenged {
+            account,
+            salted_password,
+            gs2_header,
+            client_first_bare,
+            server_first,
+            nonce,
+        } = self
+        else {
+            return None;
+        };
+        let (without_proof, proof) = message.rsplit_once(",p=")?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attributes.next()?.strip_prefix("c=")?;
+        if BASE64_STANDARD.decode(channel_binding).ok()? != gs2_header.as_bytes() {
+            return None;
+        }
+        if attributes.next()?.strip_prefix("r=")? != nonce {
+            return None;
+        }
+        let proof = BASE64_STANDARD.decode(proof).ok()?;
+
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_key = hmac_sha256(salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return None;
+        }
+        let recovered_key = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+        let valid: bool = Sha256::digest(&recovered_key)
+            .as_slice()
+            .ct_eq(stored_key.as_slice())
+            .into();
+        let account = account.clone().filter(|_| valid)?;
+
+        let server_key = hmac_sha256(salted_password, b"Server Key");
+        let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", BASE64_STANDARD.encode(server_signature));
+        Some((account, server_final.into_bytes()))
+    }
+}
+
+#[async_trait]
+impl Mechanism for ScramSha256 {
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step {
+        let response = match response {
+            Some(response) => String::from_utf8_lossy(&response).to_string(),
+            None if matches!(self, ScramSha256::Start) => return Step::Challenge(Vec::new()),
+            None => return Step::Failure,
+        };
+        match self {
+            ScramSha256::Start => {
+                match ScramSha256::client_first(&response, credentials.accounts) {
+                    Some((state, challenge)) => {
+                        *self = state;
+                        Step::Challenge(challenge)
+                    }
+                    None => Step::Failure,
+                }
+            }
+            ScramSha256::Challenged { .. } => match self.client_final(&response) {
+                Some((account, server_final)) => {
+                    *self = ScramSha256::Verified(account);
+                    Step::Challenge(server_final)
+                }
+                None => Step::Failure,
+            },
+            // The client acknowledges the server signature with an empty response.
+            ScramSha256::Verified(account) => Step::Success(account.clone()),
+        }
+    }
+}
+
+/// `Bearer <token>`, with the scheme matched case-insensitively.
+fn bearer_token(auth: &str) -> Option<&str> {
+    let (scheme, token) = auth.split_once(' ')?;
+    (scheme.eq_ignore_ascii_case("Bearer") && !token.is_empty()).then_some(token)
+}
+
+/// Checks a bearer token and binds the session to its mailbox. `user`, when the
+/// client names one, must be that mailbox.
+async fn bearer_account(
+    credentials: Credentials<'_>,
+    user: Option<&str>,
+    token: &str,
+) -> Result<Option<Account>, anyhow::Error> {
+    let tokens = match credentials.tokens {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+    let mailbox = match tokens.mailbox(token).await {
+        Ok(Some(mailbox)) => mailbox,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            println!(
+                "{}  auth: unable to verify access token: {}",
+                Local::now().format("%Y/%m/%d %H:%M:%S"),
+                e
+            );
+            return Err(e);
+        }
+    };
+    if user.is_some_and(|user| !user.eq_ignore_ascii_case(&mailbox)) {
+        return Ok(None);
+    }
+    Ok(Some(Account {
+        user: mailbox.clone(),
+        senders: Some(vec![mailbox]),
+        access_token: Some(token.to_string()),
+        ..Default::default()
+    }))
+}
+
+/// Google's XOAUTH2: `user=<address>^Aauth=Bearer <token>^A^A`.
+struct XOAuth2 {
+    /// The error challenge has been sent; the client's reply to it ends the exchange.
+    rejected: bool,
+}
+
+#[async_trait]
+impl Mechanism for XOAuth2 {
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step {
+        if self.rejected {
+            return Step::Failure;
+        }
+        let response = match response {
+            Some(response) => String::from_utf8_lossy(&response).to_string(),
+            None => return Step::Challenge(Vec::new()),
+        };
+        let mut user = None;
+        let mut token = None;
+        for field in response.split('\x01') {
+            if let Some(value) = field.strip_prefix("user=") {
+                user = Some(value);
+            } else if let Some(value) = field.strip_prefix("auth=") {
+                token = bearer_token(value);
+            }
+        }
+        let (user, token) = match (user, token) {
+            (Some(user), Some(token)) => (user, token),
+            _ => return Step::Failure,
+        };
+        match bearer_account(credentials, Some(user), token).await {
+            Ok(Some(account)) => Step::Success(account),
+            Ok(None) => {
+                self.rejected = true;
+                let error = format!(
+                    r#"{{"status":"401","schemes":"Bearer","scope":"{}"}}"#,
+                    OAUTH_SCOPE
+                );
+                Step::Challenge(error.into_bytes())
+            }
+            Err(_) => Step::Unavailable,
+        }
+    }
+}
+
+/// RFC 7628, without channel binding.
+struct OAuthBearer {
+    /// The error challenge has been sent; the client's reply to it ends the exchange.
+    rejected: bool,
+}
+
+#[async_trait]
+impl Mechanism for OAuthBearer {
+    async fn step(&mut self, response: Option<Vec<u8>>, credentials: Credentials<'_>) -> Step {
+        if self.rejected {
+            return Step::Failure;
+        }
+        let response = match response {
+            Some(response) => String::from_utf8_lossy(&response).to_string(),
+            None => return Step::Challenge(Vec::new()),
+        };
+        let (gs2_header, pairs) = match response.split_once('\x01') {
+            Some(x) => x,
+            None => return Step::Failure,
+        };
+        let user = match gs2_header.split(',').collect::<Vec<_>>()[..] {
+            ["n" | "y", "", ""] => None,
+            ["n" | "y", authzid, ""] => {
+                match authzid.strip_prefix("a=").and_then(decode_saslname) {
+                    Some(user) => Some(user),
+                    None => return Step::Failure,
+                }
+            }
+            _ => return Step::Failure,
+        };
+        let token = pairs
+            .split('\x01')
+            .find_map(|pair| pair.strip_prefix("auth="))
+            .and_then(bearer_token);
+        let token = match token {
+            Some(token) => token,
+            None => return Step::Failure,
+        };
+        match bearer_account(credentials, user.as_deref(), token).await {
+            Ok(Some(account)) => Step::Success(account),
+            Ok(None) => {
+                self.rejected = true;
+                let error = format!(r#"{{"status":"invalid_token","scope":"{}"}}"#, OAUTH_SCOPE);
+                Step::Challenge(error.into_bytes())
+            }
+            Err(_) => Step::Unavailable,
+        }
+    }
+}
@@ -1,5 +1,12 @@
-use anyhow::anyhow;
+use crate::dsn;
+use crate::envelope::{parse_mail_from, parse_rcpt_to, Parameters, PathArg};
+use crate::mail_sink::{DeliveryError, MailSink};
+use crate::reply::Reply;
+use crate::sasl::{Credentials, Mechanism, Registry, Step, TokenVerifier};
+use crate::socket::Socket;
+use crate::tls;
 use base64::prelude::*;
+use chrono::Local;
 use serde::{Deserialize, Serialize};
 use std::io;
 use std::sync::Arc;
@@ -8,28 +15,52 @@ use tokio::io::AsyncBufReadExt;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::sync::RwLock;
 use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::CertificateDer;
 use tokio_rustls::{rustls, TlsAcceptor};
+
+/// Message size limit used when `max_size` is not set in `config.json`.
+pub const DEFAULT_MAX_SIZE: usize = 73400320;
+
+/// Longest command line, CRLF included. RFC 4954 raises RFC 5321's 512 octets so AUTH can carry tokens.
+const MAX_LINE_LENGTH: usize = 12288;
+
+/// Failed AUTH attempts after which the session is closed.
+const MAX_AUTH_FAILURES: u32 = 3;
+
 pub struct Mail<S>
 where
     S: AsyncReadExt + AsyncWriteExt + Sync + Send + Unpin,
 {
     pub mail_data: MailData,
+    sink: Arc<dyn MailSink>,
     host: String,
-    user: String,
-    passwd: String,
+    default_name: String,
+    accounts: Vec<Account>,
+    account: Option<Account>,
+    sasl: Registry,
+    tokens: Option<Arc<dyn TokenVerifier>>,
+    /// The SASL exchange in progress while the lock is `Auth`.
+    mechanism: Option<Box<dyn Mechanism>>,
+    max_size: usize,
     stream: Arc<RwLock<S>>,
     status: Status,
     tls_type: Option<TlsType>,
     tls_cert: Option<Arc<rustls::ServerConfig>>,
-    auth_type: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MailData {
     pub from: Addr,
     pub to: Vec<Addr>,
+    /// ESMTP parameters given with `MAIL FROM`.
+    pub mail_params: Parameters,
+    /// ESMTP parameters given with each `RCPT TO`, in the same order as `to`.
+    pub rcpt_params: Vec<Parameters>,
     pub subject: String,
-    pub body: String,
+    /// Raw RFC 5322 message as received, dot-unstuffed but otherwise untouched.
+    pub body: Vec<u8>,
+    /// Sends with this Lark user access token instead of one stored for the sender's mailbox.
+    pub access_token: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -38,11 +69,55 @@ pub struct Addr {
     pub name: String,
 }
 
-pub struct MailConfig {
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Account {
     pub user: String,
+    #[serde(default)]
     pub passwd: String,
+    /// Addresses this account may use in `MAIL FROM`, either exact or `*@domain`.
+    /// `None` allows any sender.
+    pub senders: Option<Vec<String>>,
+    /// Subject common names or subjectAltNames of verified client certificates that log in as this account without AUTH.
+    #[serde(default)]
+    pub client_cert_names: Vec<String>,
+    /// Uids of local processes that log in as this account without AUTH when connecting over a Unix socket.
+    #[serde(default)]
+    pub peer_uids: Vec<u32>,
+    /// Lark user access token of a session authenticated with XOAUTH2 or OAUTHBEARER.
+    #[serde(skip)]
+    pub access_token: Option<String>,
+}
+
+impl Account {
+    pub fn may_send_as(&self, address: &str) -> bool {
+        let senders = match &self.senders {
+            Some(senders) => senders,
+            None => return true,
+        };
+        let address = address.to_lowercase();
+        senders.iter().any(|sender| {
+            let sender = sender.to_lowercase();
+            match sender.strip_prefix("*@") {
+                Some(domain) => address
+                    .rsplit_once('@')
+                    .is_some_and(|(_, address_domain)| address_domain == domain),
+                None => sender == "*" || sender == address,
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct MailConfig {
+    pub accounts: Vec<Account>,
     pub host: String,
     pub default_name: String,
+    /// Largest message accepted, in bytes; advertised through the SIZE extension.
+    pub max_size: usize,
+    /// SASL mechanisms offered through AUTH.
+    pub sasl: Registry,
+    /// Checks the Lark user access tokens of XOAUTH2 and OAUTHBEARER.
+    pub tokens: Option<Arc<dyn TokenVerifier>>,
     pub tls_type: Option<TlsType>,
     pub tls_cert: Option<Arc<rustls::ServerConfig>>,
 }
@@ -56,52 +131,132 @@ pub enum TlsType {
 struct Status {
     has_tls: bool,
     auth: bool,
+    /// A `MAIL FROM` has been accepted and the transaction is not finished yet.
+    transaction: bool,
+    /// The DATA being received went over `max_size` and is being discarded.
+    oversized: bool,
+    /// The command line being read went over `MAX_LINE_LENGTH`; the rest of it is discarded.
+    long_line: bool,
+    /// The last DATA line ended in CRLF, so a `.` line after it ends the message.
+    crlf: bool,
+    /// The transaction has received at least one BDAT chunk.
+    chunking: bool,
+    /// `MAIL FROM` declared `BODY=BINARYMIME`, so only BDAT may carry the message.
+    binarymime: bool,
+    auth_failures: u32,
     quit: bool,
     starttls: bool,
-    auth_login_begin: bool,
     lock: LockMode,
 }
 
 #[derive(PartialEq)]
 enum LockMode {
-    NULL,
-    DATA,
-    AUTH,
+    Null,
+    Data,
+    Auth,
+    /// The next `size` bytes on the stream are a BDAT chunk; `discard` drops it after a rejected BDAT.
+    Chunk {
+        size: usize,
+        last: bool,
+        discard: bool,
+    },
+}
+fn empty_mail_data(default_name: &str) -> MailData {
+    MailData {
+        from: Addr {
+            mail_address: "".to_string(),
+            name: default_name.to_string(),
+        },
+        to: Vec::new(),
+        mail_params: Parameters::default(),
+        rcpt_params: Vec::new(),
+        subject: String::new(),
+        body: Vec::new(),
+        access_token: None,
+    }
 }
+
+fn too_large() -> Reply {
+    Reply::new(
+        552,
+        "5.3.4",
+        "Message size exceeds fixed maximum message size",
+    )
+}
+
+fn decode_response(response: &str) -> Result<Vec<u8>, Reply> {
+    BASE64_STANDARD
+        .decode(response)
+        .map_err(|_| Reply::new(501, "5.5.2", "Cannot decode response"))
+}
+
 pub fn plain_encode(user: &str, password: &str) -> String {
     BASE64_STANDARD.encode(format!("\x00{}\x00{}", user, password))
 }
 
+/// Reads exactly `size` bytes of a BDAT chunk, keeping them only if `keep` is set.
+/// Returns `None` if the client disconnects before the chunk is complete.
+async fn read_chunk<R>(
+    reader: &mut R,
+    size: usize,
+    keep: bool,
+) -> Result<Option<Vec<u8>>, anyhow::Error>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut chunk = Vec::new();
+    let mut buf = vec![0; 65536];
+    let mut remaining = size;
+    while remaining > 0 {
+        let read = timeout(
+            Duration::from_secs(10),
+            reader.read(&mut buf[..remaining.min(65536)]),
+        )
+        .await??;
+        if read == 0 {
+            return Ok(None);
+        }
+        if keep {
+            chunk.extend_from_slice(&buf[..read]);
+        }
+        remaining -= read;
+    }
+    Ok(Some(chunk))
+}
+
 impl<S> Mail<S>
 where
     S: AsyncReadExt + AsyncWriteExt + Sync + Send + Unpin,
 {
-    pub fn new(stream: S, config: Arc<MailConfig>) -> Self {
+    pub fn new(stream: S, config: Arc<MailConfig>, sink: Arc<dyn MailSink>) -> Self {
         Mail {
-            mail_data: MailData {
-                from: Addr {
-                    mail_address: "".to_string(),
-                    name: config.default_name.clone(),
-                },
-                to: Vec::new(),
-                subject: String::new(),
-                body: String::new(),
-            },
+            mail_data: empty_mail_data(&config.default_name),
+            sink,
             host: config.host.clone(),
-            user: config.user.clone(),
-            passwd: config.passwd.clone(),
+            default_name: config.default_name.clone(),
+            accounts: config.accounts.clone(),
+            account: None,
+            sasl: config.sasl.clone(),
+            tokens: config.tokens.clone(),
+            mechanism: None,
+            max_size: config.max_size,
             stream: Arc::new(RwLock::new(stream)),
             status: Status {
                 has_tls: false,
                 auth: false,
+                transaction: false,
+                oversized: false,
+                long_line: false,
+                crlf: false,
+                chunking: false,
+                binarymime: false,
+                auth_failures: 0,
                 quit: false,
                 starttls: false,
-                auth_login_begin: false,
-                lock: LockMode::NULL,
+                lock: LockMode::Null,
             },
             tls_cert: config.tls_cert.clone(),
             tls_type: config.tls_type.clone(),
-            auth_type: "".to_string(),
         }
     }
 }
@@ -124,6 +279,15 @@ where
                     let conn = TlsAcceptor::from(tls_cert.clone())
                         .accept(&mut *stream)
                         .await?;
+                    let peer = conn
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|x| x.first())
+                        .cloned();
+                    if let Some(cert) = peer {
+                        self.client_certificate(&cert);
+                    }
                     self.io(BufReader::new(conn)).await?;
                 } else {
                     self.io(BufReader::new(&mut *stream)).await?;
@@ -139,247 +303,631 @@ where
         Ok(())
     }
 
-    fn check_mail(&self) -> bool {
-        self.mail_data.to.len() != 0
-            && self.mail_data.from.mail_address.len() != 0
-            && self.mail_data.body.len() != 0
+    /// Logs the session in as the account a verified client certificate names, if any.
+    fn client_certificate(&mut self, cert: &CertificateDer) {
+        let names = tls::certificate_names(cert);
+        let account = self.accounts.iter().find(|account| {
+            account
+                .client_cert_names
+                .iter()
+                .any(|x| names.iter().any(|name| name.eq_ignore_ascii_case(x)))
+        });
+        match account {
+            Some(account) => {
+                self.account = Some(account.clone());
+                self.status.auth = true;
+            }
+            None => println!(
+                "{}  tls: client certificate {:?} matches no account",
+                Local::now().format("%Y/%m/%d %H:%M:%S"),
+                names
+            ),
+        }
+    }
+
+    /// Logs the session in as the account a Unix socket peer's uid is mapped to, if any.
+    fn peer_uid(&mut self, uid: u32) {
+        if let Some(account) = self.accounts.iter().find(|x| x.peer_uids.contains(&uid)) {
+            self.account = Some(account.clone());
+            self.status.auth = true;
+        }
     }
 
-    async fn io<IO>(&mut self, mut reader: IO) -> Result<(), anyhow::Error>
+    async fn io<T>(&mut self, mut reader: BufReader<T>) -> Result<(), anyhow::Error>
     where
-        IO: AsyncBufReadExt + AsyncWriteExt + Sync + Send + Unpin,
+        T: AsyncReadExt + AsyncWriteExt + Sync + Send + Unpin,
     {
+        let mut replies = Vec::new();
         if !self.status.has_tls
             || self.tls_type.is_some() && *self.tls_type.as_ref().unwrap() == TlsType::SSL
         {
-            reader
-                .write_all(format!("220 {} Esmtp smtp2larkapi\r\n", &self.host).as_bytes())
-                .await?;
+            let greeting = Reply::plain(220, format!("{} Esmtp smtp2larkapi", &self.host));
+            replies.extend_from_slice(greeting.to_string().as_bytes());
         }
         loop {
-            let mut request = String::new();
-            match timeout(Duration::from_secs(10), reader.read_line(&mut request)).await? {
-                Ok(_) => {
-                    if cfg!(debug_assertions) {
-                        print!("receive:  {}", &request);
-                    }
+            // RFC 2920: replies are held back while pipelined commands are still buffered.
+            if reader.buffer().is_empty() && !replies.is_empty() {
+                reader.write_all(&replies).await?;
+                reader.flush().await?;
+                replies.clear();
+            }
+
+            let mut request = Vec::new();
+            if let LockMode::Chunk { size, discard, .. } = self.status.lock {
+                match read_chunk(&mut reader, size, !discard).await? {
+                    Some(chunk) => request = chunk,
+                    None => return Ok(()),
                 }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                        return Ok(());
+                if cfg!(debug_assertions) {
+                    println!("receive:  <{} octets>", size);
+                }
+            } else {
+                // Overlong lines are read in pieces so they never sit in memory whole.
+                let limit = match self.status.lock {
+                    LockMode::Data => self.max_size + 1,
+                    _ => MAX_LINE_LENGTH,
+                };
+                let mut line_reader = (&mut reader).take(limit as u64);
+                match timeout(
+                    Duration::from_secs(10),
+                    line_reader.read_until(b'\n', &mut request),
+                )
+                .await?
+                {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => {
+                        if cfg!(debug_assertions) {
+                            print!("receive:  {}", String::from_utf8_lossy(&request));
+                        }
+                    }
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::UnexpectedEof {
+                            return Ok(());
+                        }
+                        return Err(e.into());
                     }
-                    return Err(e.into());
                 }
             }
 
             match self.scheduler(&request).await {
                 Ok(response) => {
-                    if response.len() != 0 {
+                    if let Some(response) = response {
                         if cfg!(debug_assertions) {
                             print!("send:  {}", response);
                         }
 
-                        reader.write_all(response.as_bytes()).await?;
-                        if self.status.quit {
-                            return Ok(());
-                        }
-                        if self.status.starttls {
+                        replies.extend_from_slice(response.to_string().as_bytes());
+                        if self.status.quit || self.status.starttls {
+                            // Anything pipelined after STARTTLS is dropped along with the buffer.
                             self.status.starttls = false;
+                            reader.write_all(&replies).await?;
+                            reader.flush().await?;
                             return Ok(());
                         }
                     }
                 }
                 Err(e) => {
-                    if self.check_mail() {
-                        return Ok(());
-                    }
+                    let response = match e.downcast_ref::<Reply>() {
+                        Some(reply) => reply.clone(),
+                        None => Reply::new(
+                            451,
+                            "4.3.0",
+                            "Requested action aborted: local error in processing",
+                        ),
+                    };
                     if cfg!(debug_assertions) {
-                        print!("send:  {}", e.to_string());
+                        print!("send:  {}", response);
+                    }
+                    replies.extend_from_slice(response.to_string().as_bytes());
+                    if self.status.quit {
+                        reader.write_all(&replies).await?;
+                        reader.flush().await?;
+                        return Err(e);
                     }
-                    reader.write_all(e.to_string().as_bytes()).await?;
-                    return Err(e);
                 }
             }
         }
     }
 
-    async fn scheduler(&mut self, request: &str) -> Result<String, anyhow::Error> {
+    /// Handles one line, or one BDAT chunk. `None` means the input needs no reply of its own.
+    async fn scheduler(&mut self, line: &[u8]) -> Result<Option<Reply>, anyhow::Error> {
+        if self.status.lock == LockMode::Data {
+            return self.data_line(line).await;
+        }
+        if let LockMode::Chunk {
+            size,
+            last,
+            discard,
+        } = self.status.lock
+        {
+            self.status.lock = LockMode::Null;
+            if discard {
+                return Ok(None);
+            }
+            return self.bdat_chunk(line, size, last).await.map(Some);
+        }
+        if self.status.long_line || line.len() >= MAX_LINE_LENGTH && !line.ends_with(b"\n") {
+            self.status.long_line = !line.ends_with(b"\n");
+            if self.status.long_line {
+                return Ok(None);
+            }
+            self.status.lock = LockMode::Null;
+            self.mechanism = None;
+            return Err(Reply::new(500, "5.5.2", "Line too long").into());
+        }
+
+        let request = String::from_utf8_lossy(line);
+        let request = request.as_ref();
         let handle = match self.status.lock {
-            LockMode::NULL => request
+            LockMode::Auth => "AUTH".to_string(),
+            _ => request
                 .split_whitespace()
                 .next()
                 .unwrap_or("")
                 .to_uppercase(),
-            LockMode::DATA => "DATA".to_string(),
-            LockMode::AUTH => "AUTH".to_string(),
         };
 
-        let response: Result<String, anyhow::Error> = match handle.as_str() {
+        let response: Result<Reply, anyhow::Error> = match handle.as_str() {
             "HELO" | "EHLO" => self.helo().await,
             "STARTTLS" => self.starttls().await,
-            "MAIL" => self.mail(&request).await,
-            "RCPT" => self.rcpt(&request).await,
-            "DATA" => self.data(&request).await,
+            "MAIL" => self.mail(request).await,
+            "RCPT" => self.rcpt(request).await,
+            "DATA" => self.data().await,
+            "BDAT" => return self.bdat(request).await,
+            "RSET" => self.rset().await,
+            "NOOP" => Ok(Reply::new(250, "2.0.0", "OK")),
+            "VRFY" => self.vrfy(request).await,
+            "HELP" => self.help().await,
+            "EXPN" | "TURN" | "ETRN" | "ATRN" | "SEND" | "SOML" | "SAML" => {
+                Err(Reply::new(502, "5.5.1", "Command not implemented").into())
+            }
             "QUIT" => self.quit().await,
-            "AUTH" => self.auth(&request).await,
-            _ => Err(anyhow!("500 Unknown command")),
+            "AUTH" => self.auth(request).await,
+            _ => Err(Reply::new(500, "5.5.2", "Unknown command").into()),
         };
 
-        response
+        response.map(Some)
+    }
+
+    /// Drops the envelope and body of the current transaction.
+    fn reset_transaction(&mut self) -> MailData {
+        self.status.transaction = false;
+        self.status.oversized = false;
+        self.status.chunking = false;
+        self.status.binarymime = false;
+        std::mem::replace(&mut self.mail_data, empty_mail_data(&self.default_name))
     }
 
-    async fn helo(&self) -> Result<String, anyhow::Error> {
-        let tls = if self.tls_type.is_some()
+    async fn helo(&mut self) -> Result<Reply, anyhow::Error> {
+        self.reset_transaction();
+        let mut reply = Reply::plain(250, &self.host)
+            .line("PIPELINING")
+            .line(format!("SIZE {}", self.max_size));
+        if self.tls_type.is_some()
             && *self.tls_type.as_ref().unwrap() == TlsType::STARTTLS
             && !self.status.has_tls
         {
-            "250-STARTTLS\r\n"
-        } else {
-            ""
-        };
-        Ok(format!("250-{}\r\n250-PIPELINING\r\n250-SIZE 73400320\r\n{}250-AUTH LOGIN PLAIN\r\n250-AUTH=LOGIN\r\n250-SMTPUTF8\r\n250 8BITMIME\r\n", &self.host, tls))
+            reply = reply.line("STARTTLS");
+        }
+        let mechanisms = self.sasl.names();
+        if !mechanisms.is_empty() {
+            reply = reply.line(format!("AUTH {}", mechanisms.join(" ")));
+        }
+        // Old Outlook versions only recognise the pre-standard form.
+        if mechanisms.contains(&"LOGIN") {
+            reply = reply.line("AUTH=LOGIN");
+        }
+        reply = reply.line("ENHANCEDSTATUSCODES");
+        if self.sink.reports_status() {
+            reply = reply.line("DSN");
+        }
+        Ok(reply
+            .line("SMTPUTF8")
+            .line("8BITMIME")
+            .line("CHUNKING")
+            .line("BINARYMIME"))
     }
 
-    async fn mail(&mut self, request: &str) -> Result<String, anyhow::Error> {
+    async fn mail(&mut self, request: &str) -> Result<Reply, anyhow::Error> {
         if !self.status.auth {
-            return Err(anyhow!("Client is not authenticated"));
-        }
-        let left_index = request
-            .find("<")
-            .ok_or(anyhow!("500 Unable to parse content\r\n"))?
-            + 1;
-        let right_index = request
-            .find(">")
-            .ok_or(anyhow!("500 Unable to parse content\r\n"))?;
-        if right_index - left_index < 1 {
-            return Err(anyhow!("500"));
-        }
-        let from = &request[left_index..right_index];
-        self.mail_data.from.mail_address = from.to_string();
-
-        Ok("250 OK\r\n".to_string())
+            return Err(Reply::new(530, "5.7.0", "Authentication required").into());
+        }
+        if self.status.transaction {
+            return Err(Reply::new(503, "5.5.1", "Sender already specified").into());
+        }
+        let PathArg { address, params } = parse_mail_from(request)?;
+        let dsn = self.sink.reports_status();
+        for keyword in params.keywords() {
+            let known = ["SIZE", "BODY", "SMTPUTF8"].contains(&keyword)
+                || dsn && ["RET", "ENVID"].contains(&keyword);
+            if !known {
+                return Err(
+                    Reply::new(555, "5.5.4", format!("Unsupported parameter {}", keyword)).into(),
+                );
+            }
+        }
+        let binarymime = match params.get("BODY").map(|x| x.to_uppercase()).as_deref() {
+            None | Some("7BIT") | Some("8BITMIME") => false,
+            Some("BINARYMIME") => true,
+            Some(_) => return Err(Reply::new(501, "5.5.4", "Invalid BODY parameter").into()),
+        };
+        if params.contains("SIZE") {
+            let size: u64 = params
+                .get("SIZE")
+                .and_then(|x| x.parse().ok())
+                .ok_or(Reply::new(501, "5.5.4", "Invalid SIZE parameter"))?;
+            if size > self.max_size as u64 {
+                return Err(too_large().into());
+            }
+        }
+        dsn::check_mail_params(&params)?;
+        if params.get("SMTPUTF8").is_some() {
+            return Err(Reply::new(501, "5.5.4", "SMTPUTF8 takes no value").into());
+        }
+        if !address.is_ascii() && !params.contains("SMTPUTF8") {
+            return Err(Reply::new(553, "5.6.7", "Non-ASCII address requires SMTPUTF8").into());
+        }
+        if let Some(account) = &self.account {
+            if !account.may_send_as(&address) {
+                return Err(Reply::new(
+                    550,
+                    "5.7.1",
+                    format!(
+                        "Sender address rejected: not owned by user {}",
+                        account.user
+                    ),
+                )
+                .into());
+            }
+        }
+        self.mail_data.from.mail_address = address;
+        self.mail_data.mail_params = params;
+        self.mail_data.access_token = self.account.as_ref().and_then(|x| x.access_token.clone());
+        self.status.transaction = true;
+        self.status.binarymime = binarymime;
+
+        Ok(Reply::new(250, "2.1.0", "OK"))
     }
 
-    async fn rcpt(&mut self, request: &str) -> Result<String, anyhow::Error> {
+    async fn rcpt(&mut self, request: &str) -> Result<Reply, anyhow::Error> {
         if !self.status.auth {
-            return Err(anyhow!("Client is not authenticated"));
-        }
-        let left_index = request
-            .find("<")
-            .ok_or(anyhow!("500 Unable to parse content\r\n"))?
-            + 1;
-        let right_index = request
-            .find(">")
-            .ok_or(anyhow!("500 Unable to parse content\r\n"))?;
-        if right_index - left_index < 1 {
-            return Err(anyhow!("500"));
-        }
-        let to = &request[left_index..right_index];
+            return Err(Reply::new(530, "5.7.0", "Authentication required").into());
+        }
+        if !self.status.transaction {
+            return Err(Reply::new(503, "5.5.1", "Need MAIL command").into());
+        }
+        let PathArg { address, params } = parse_rcpt_to(request)?;
+        for keyword in params.keywords() {
+            if !(self.sink.reports_status() && ["NOTIFY", "ORCPT"].contains(&keyword)) {
+                return Err(
+                    Reply::new(555, "5.5.4", format!("Unsupported parameter {}", keyword)).into(),
+                );
+            }
+        }
+        dsn::check_rcpt_params(&params)?;
+        if !address.is_ascii() && !self.mail_data.mail_params.contains("SMTPUTF8") {
+            return Err(Reply::new(553, "5.6.7", "Non-ASCII address requires SMTPUTF8").into());
+        }
         self.mail_data.to.push(Addr {
-            mail_address: to.to_string(),
+            mail_address: address,
             name: "".to_string(),
         });
+        self.mail_data.rcpt_params.push(params);
 
-        Ok("250 OK\r\n".to_string())
+        Ok(Reply::new(250, "2.1.5", "OK"))
     }
 
-    async fn data(&mut self, request: &str) -> Result<String, anyhow::Error> {
+    async fn data(&mut self) -> Result<Reply, anyhow::Error> {
         if !self.status.auth {
-            return Err(anyhow!("Client is not authenticated"));
+            return Err(Reply::new(530, "5.7.0", "Authentication required").into());
+        }
+        if self.mail_data.to.is_empty() {
+            return Err(Reply::new(503, "5.5.1", "Need RCPT command").into());
+        }
+        if self.status.chunking {
+            return Err(Reply::new(503, "5.5.1", "DATA not allowed after BDAT").into());
         }
-        if request == ".\r\n" {
-            self.status.lock = LockMode::NULL;
-            return Ok("250 OK\r\n".to_string());
+        if self.status.binarymime {
+            return Err(Reply::new(503, "5.6.1", "BINARYMIME requires BDAT").into());
         }
+        self.status.lock = LockMode::Data;
+        self.status.crlf = true;
+        Ok(Reply::plain(
+            354,
+            "Start mail input; end with <CRLF>.<CRLF>",
+        ))
+    }
 
-        if self.status.lock == LockMode::DATA {
-            if request == "..\r\n" {
-                self.mail_data.body += ".\r\n"
-            } else {
-                self.mail_data.body += request;
+    async fn data_line(&mut self, line: &[u8]) -> Result<Option<Reply>, anyhow::Error> {
+        // Only <CRLF>.<CRLF> ends the message: accepting a dot after a bare LF lets a
+        // client smuggle a second message past servers that disagree on where this one ends.
+        if line == b".\r\n" && self.status.crlf {
+            self.status.lock = LockMode::Null;
+            if self.status.oversized {
+                self.reset_transaction();
+                return Err(too_large().into());
+            }
+            return self.finish_mail().await.map(Some);
+        }
+        self.status.crlf = line.ends_with(b"\r\n");
+
+        // RFC 5321 4.5.2: drop the first character of every line that starts with a dot.
+        let line = line.strip_prefix(b".").unwrap_or(line);
+        // Legacy senders end lines with a bare LF; store them with CRLF like every other line.
+        let mut line = line.to_vec();
+        if line.ends_with(b"\n") && !self.status.crlf {
+            line.insert(line.len() - 1, b'\r');
+        }
+        if self.mail_data.body.len() + line.len() > self.max_size {
+            self.status.oversized = true;
+            self.mail_data.body = Vec::new();
+        }
+        if !self.status.oversized {
+            self.mail_data.body.extend_from_slice(&line);
+        }
+        Ok(None)
+    }
+
+    async fn bdat(&mut self, request: &str) -> Result<Option<Reply>, anyhow::Error> {
+        let mut args = request.split_whitespace().skip(1);
+        let size = args.next().and_then(|x| x.parse::<usize>().ok());
+        let last = args.next();
+        let (size, last) = match (size, last, args.next()) {
+            (Some(size), None, None) => (size, false),
+            (Some(size), Some(last), None) if last.eq_ignore_ascii_case("LAST") => (size, true),
+            _ => return Err(Reply::new(501, "5.5.4", "Syntax: BDAT <size> [LAST]").into()),
+        };
+
+        // The chunk follows the command regardless of our answer, so a rejected one is still consumed.
+        let rejection = if !self.status.auth {
+            Some(Reply::new(530, "5.7.0", "Authentication required"))
+        } else if !self.status.transaction {
+            Some(Reply::new(503, "5.5.1", "Need MAIL command"))
+        } else if self.mail_data.to.is_empty() {
+            Some(Reply::new(503, "5.5.1", "Need RCPT command"))
+        } else if size > self.max_size.saturating_sub(self.mail_data.body.len()) {
+            // `size` comes from the client, so compare without adding to it.
+            self.reset_transaction();
+            Some(too_large())
+        } else {
+            None
+        };
+        self.status.lock = LockMode::Chunk {
+            size,
+            last,
+            discard: rejection.is_some(),
+        };
+        match rejection {
+            Some(reply) => Err(reply.into()),
+            None => {
+                self.status.chunking = true;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn bdat_chunk(
+        &mut self,
+        chunk: &[u8],
+        size: usize,
+        last: bool,
+    ) -> Result<Reply, anyhow::Error> {
+        self.mail_data.body.extend_from_slice(chunk);
+        if !last {
+            return Ok(Reply::new(
+                250,
+                "2.0.0",
+                format!("{} octets received", size),
+            ));
+        }
+        self.finish_mail().await
+    }
+
+    /// Hands the finished transaction to the sink and builds the final reply.
+    async fn finish_mail(&mut self) -> Result<Reply, anyhow::Error> {
+        let mail_data = self.reset_transaction();
+        let mail_to = mail_data
+            .to
+            .iter()
+            .map(|x| x.mail_address.clone())
+            .collect::<Vec<_>>();
+        println!(
+            "{}  Received an email request to send: {:?}",
+            Local::now().format("%Y/%m/%d %H:%M:%S"),
+            &mail_to
+        );
+        match self.sink.deliver(mail_data).await {
+            Ok(delivery) => Ok(match delivery.message_id {
+                Some(id) => Reply::new(250, "2.0.0", format!("OK queued as {}", id)),
+                None => Reply::new(250, "2.0.0", "OK"),
+            }),
+            Err(e) => {
+                println!(
+                    "{}  to:{:?}  {}",
+                    Local::now().format("%Y/%m/%d %H:%M:%S"),
+                    &mail_to,
+                    e
+                );
+                Ok(match e.downcast_ref::<DeliveryError>() {
+                    Some(e) => Reply::from(e),
+                    None => Reply::new(
+                        451,
+                        "4.3.0",
+                        "Requested action aborted: local error in processing",
+                    ),
+                })
             }
+        }
+    }
+
+    async fn rset(&mut self) -> Result<Reply, anyhow::Error> {
+        self.reset_transaction();
+        Ok(Reply::new(250, "2.0.0", "OK"))
+    }
 
-            return Ok(String::new());
+    async fn vrfy(&self, request: &str) -> Result<Reply, anyhow::Error> {
+        if request.split_whitespace().nth(1).is_none() {
+            return Err(Reply::new(501, "5.5.4", "Syntax: VRFY <address>").into());
         }
+        Ok(Reply::new(
+            252,
+            "2.5.0",
+            "Cannot VRFY user, but will accept message and attempt delivery",
+        ))
+    }
 
-        self.status.lock = LockMode::DATA;
-        Ok("354 Start mail input; end with <CRLF>.<CRLF>\r\n".to_string())
+    async fn help(&self) -> Result<Reply, anyhow::Error> {
+        Ok(Reply::new(214, "2.0.0", "Commands supported:")
+            .line("HELO EHLO STARTTLS AUTH MAIL RCPT DATA BDAT RSET NOOP VRFY HELP QUIT"))
     }
 
-    async fn quit(&mut self) -> Result<String, anyhow::Error> {
+    async fn quit(&mut self) -> Result<Reply, anyhow::Error> {
         self.status.quit = true;
-        Ok("221 Bye\r\n".to_string())
+        Ok(Reply::new(221, "2.0.0", "Bye"))
     }
 
-    async fn auth(&mut self, request: &str) -> Result<String, anyhow::Error> {
+    async fn auth(&mut self, request: &str) -> Result<Reply, anyhow::Error> {
+        if self.status.lock == LockMode::Auth {
+            return self.auth_response(request).await;
+        }
         if self.tls_type.is_some() && !self.status.has_tls {
-            return Err(anyhow!("530 5.7.0 Must issue a STARTTLS command first\r\n"));
+            return Err(Reply::new(530, "5.7.0", "Must issue a STARTTLS command first").into());
+        }
+        if self.status.auth {
+            return Err(Reply::new(503, "5.5.1", "Already authenticated").into());
+        }
+        if self.status.transaction {
+            return Err(
+                Reply::new(503, "5.5.1", "AUTH not allowed during a mail transaction").into(),
+            );
         }
 
-        let args = if self.status.lock == LockMode::NULL {
-            let args = request.split(" ").collect::<Vec<_>>();
-            self.auth_type = args
-                .get(1)
-                .ok_or(anyhow!("500 The authentication type is incorrect.\r\n"))?
-                .trim_end()
-                .to_string();
-            Some(args)
-        } else {
-            None
+        let mut args = request.split_whitespace().skip(1);
+        let name = args
+            .next()
+            .ok_or(Reply::new(501, "5.5.4", "Syntax: AUTH <mechanism>"))?;
+        let mechanism = match self.sasl.start(name, &self.host) {
+            Some(mechanism) => mechanism,
+            None => return Err(Reply::new(504, "5.5.4", "Unrecognized authentication type").into()),
+        };
+        // RFC 4954: `=` is an empty initial response, as opposed to none at all.
+        let initial = match args.next() {
+            None => None,
+            Some("=") => Some(Vec::new()),
+            Some(initial) => Some(decode_response(initial)?),
         };
+        self.mechanism = Some(mechanism);
+        self.auth_step(initial).await
+    }
 
-        match self.auth_type.to_uppercase().as_str() {
-            "PLAIN" => {
-                let auth_plain = if args.is_some() && args.as_ref().unwrap().len() == 3 {
-                    args.unwrap()[2].trim_end()
-                } else {
-                    if self.status.lock == LockMode::AUTH {
-                        self.status.lock = LockMode::NULL;
-                        request.trim_end()
-                    } else {
-                        self.status.lock = LockMode::AUTH;
-                        return Ok("334 \r\n".to_string());
-                    }
-                };
-                if auth_plain == plain_encode(&self.user, &self.passwd) {
-                    self.status.auth = true;
-                    return Ok("235 Authentication successful\r\n".to_string());
-                }
+    /// Handles a client response line during an AUTH exchange.
+    async fn auth_response(&mut self, request: &str) -> Result<Reply, anyhow::Error> {
+        let response = request.trim_end();
+        if response == "*" {
+            self.status.lock = LockMode::Null;
+            self.mechanism = None;
+            return Err(Reply::new(501, "5.0.0", "Authentication cancelled").into());
+        }
+        match decode_response(response) {
+            Ok(response) => self.auth_step(Some(response)).await,
+            Err(e) => {
+                self.status.lock = LockMode::Null;
+                self.mechanism = None;
+                Err(e.into())
             }
-            "LOGIN" => {
-                if self.status.lock == LockMode::NULL && !self.status.auth_login_begin {
-                    self.status.lock = LockMode::AUTH;
-                    self.status.auth_login_begin = true;
-                    return Ok("334 VXNlcm5hbWU6\r\n".to_string());
-                } else if self.status.auth_login_begin
-                    && request.trim_end() == BASE64_STANDARD.encode(&self.user)
-                {
-                    self.status.auth_login_begin = false;
-                    return Ok("334 UGFzc3dvcmQ6\r\n".to_string());
-                } else if request.trim_end() == BASE64_STANDARD.encode(&self.passwd) {
-                    self.status.auth = true;
-                    self.status.lock = LockMode::NULL;
-                    return Ok("235 Authentication successful\r\n".to_string());
-                }
+        }
+    }
+
+    async fn auth_step(&mut self, response: Option<Vec<u8>>) -> Result<Reply, anyhow::Error> {
+        self.status.lock = LockMode::Null;
+        let mut mechanism = match self.mechanism.take() {
+            Some(mechanism) => mechanism,
+            None => return Err(Reply::new(503, "5.5.1", "No authentication in progress").into()),
+        };
+        let credentials = Credentials {
+            accounts: &self.accounts,
+            tokens: self.tokens.as_deref(),
+        };
+        match mechanism.step(response, credentials).await {
+            Step::Challenge(challenge) => {
+                self.mechanism = Some(mechanism);
+                self.status.lock = LockMode::Auth;
+                Ok(Reply::plain(334, BASE64_STANDARD.encode(challenge)))
             }
-            _ => {
-                self.status.quit = true;
-                return Err(anyhow!("500 The authentication type is incorrect.\r\n"));
+            Step::Success(account) => {
+                self.account = Some(account);
+                self.status.auth = true;
+                Ok(Reply::new(235, "2.7.0", "Authentication successful"))
             }
-        };
-
-        self.status.quit = true;
-        return Err(anyhow!("535 Authentication failed\r\n".to_string()));
+            Step::Failure => {
+                self.status.auth_failures += 1;
+                self.status.quit = self.status.auth_failures >= MAX_AUTH_FAILURES;
+                Err(Reply::new(535, "5.7.8", "Authentication credentials invalid").into())
+            }
+            Step::Unavailable => {
+                Err(Reply::new(454, "4.7.0", "Temporary authentication failure").into())
+            }
+        }
     }
 
-    async fn starttls(&mut self) -> Result<String, anyhow::Error> {
-        if !self.tls_type.is_some() {
-            return Err(anyhow!(
-                "454 TLS not available due to temporary reason\r\n".to_string()
-            ));
+    async fn starttls(&mut self) -> Result<Reply, anyhow::Error> {
+        if self.tls_type.is_none() {
+            return Err(
+                Reply::new(454, "4.7.0", "TLS not available due to temporary reason").into(),
+            );
         }
         self.status.has_tls = true;
         self.status.starttls = true;
-        Ok("220 Ready to start TLS\r\n".to_string())
+        Ok(Reply::new(220, "2.0.0", "Ready to start TLS"))
+    }
+}
+
+pub async fn serve<S>(
+    stream: S,
+    config: Arc<MailConfig>,
+    sink: Arc<dyn MailSink>,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncReadExt + AsyncWriteExt + Sync + Send + Unpin,
+{
+    Mail::new(stream, config, sink).run().await
+}
+
+fn spawn_session<S>(mut mail: Mail<S>)
+where
+    S: AsyncReadExt + AsyncWriteExt + Sync + Send + Unpin + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = mail.run().await {
+            println!("{} Error: {}", Local::now().format("%Y/%m/%d %H:%M:%S"), e);
+        }
+    });
+}
+
+/// Accepts connections until accepting fails, serving each one in its own task.
+///
+/// Clients on a Unix socket are logged in by their uid when an account lists it in `peer_uids`.
+pub async fn listen(
+    socket: Socket,
+    config: Arc<MailConfig>,
+    sink: Arc<dyn MailSink>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match &socket {
+            Socket::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                spawn_session(Mail::new(stream, config.clone(), sink.clone()));
+            }
+            #[cfg(unix)]
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let uid = stream.peer_cred().ok().map(|x| x.uid());
+                let mut mail = Mail::new(stream, config.clone(), sink.clone());
+                if let Some(uid) = uid {
+                    mail.peer_uid(uid);
+                }
+                spawn_session(mail);
+            }
+        }
     }
 }
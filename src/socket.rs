@@ -0,0 +1,153 @@
+#[cfg(unix)]
+use anyhow::anyhow;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// First descriptor passed by systemd, see sd_listen_fds(3).
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket SMTP sessions are accepted on.
+pub enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Socket {
+    /// The bound address, for logging.
+    pub fn describe(&self) -> String {
+        match self {
+            Socket::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(e) => format!("tcp ({})", e),
+            },
+            #[cfg(unix)]
+            Socket::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix socket".to_string(),
+                },
+                Err(e) => format!("unix socket ({})", e),
+            },
+        }
+    }
+}
+
+/// Binds a Unix socket at `path`, replacing a socket file left behind by an earlier run.
+///
+/// `mode` is an octal string such as `"660"`; `owner` and `group` are numeric ids. The socket
+/// is bound in a private directory and only renamed into place once they are applied, so it
+/// is never reachable with the permissions the umask gives it.
+#[cfg(unix)]
+pub fn bind_unix(
+    path: &str,
+    mode: Option<&str>,
+    owner: Option<u32>,
+    group: Option<u32>,
+) -> Result<UnixListener, anyhow::Error> {
+    let mode = mode
+        .map(|x| u32::from_str_radix(x, 8).map_err(|_| anyhow!("{}: invalid mode {:?}", path, x)))
+        .transpose()?;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{}: exists and is not a socket", path));
+        }
+    }
+    let target = std::path::Path::new(path);
+    let name = target
+        .file_name()
+        .ok_or(anyhow!("{}: not a socket file path", path))?;
+    let private = target.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(|e| anyhow!("{}: {}", private.display(), e))?;
+
+    let staged = private.join(name);
+    let bound = (|| {
+        let listener = UnixListener::bind(&staged).map_err(|e| anyhow!("{}: {}", path, e))?;
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))
+                .map_err(|e| anyhow!("{}: unable to set mode: {}", path, e))?;
+        }
+        if owner.is_some() || group.is_some() {
+            std::os::unix::fs::chown(&staged, owner, group)
+                .map_err(|e| anyhow!("{}: unable to change owner: {}", path, e))?;
+        }
+        // Replaces a stale socket atomically.
+        std::fs::rename(&staged, target).map_err(|e| anyhow!("{}: {}", path, e))?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private);
+    bound
+}
+
+/// Sockets passed in by systemd socket activation, by their `FileDescriptorName=`.
+///
+/// Without that setting systemd names a socket after its `.socket` unit.
+#[cfg(unix)]
+#[derive(Default)]
+pub struct Activated {
+    sockets: Vec<(String, Socket)>,
+}
+
+#[cfg(unix)]
+impl Activated {
+    /// Takes the sockets listed in `LISTEN_FDS` if `LISTEN_PID` names this process.
+    ///
+    /// Must be called at most once, as the descriptors are owned from then on.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        if pid.and_then(|x| x.parse::<u32>().ok()) != Some(std::process::id()) {
+            return Ok(Activated::default());
+        }
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|x| x.parse::<RawFd>().ok())
+            .ok_or(anyhow!("systemd: invalid LISTEN_FDS"))?;
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+
+        let mut sockets = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            let name = names.next().unwrap_or("unknown").to_string();
+            // Safety: systemd hands these descriptors to this process and nothing else owns them.
+            let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            // An address of any other family fails to convert, which is how Unix sockets are told apart.
+            let socket = if tcp.local_addr().is_ok() {
+                tcp.set_nonblocking(true)?;
+                Socket::Tcp(TcpListener::from_std(tcp)?)
+            } else {
+                let unix =
+                    unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                unix.set_nonblocking(true)?;
+                Socket::Unix(UnixListener::from_std(unix)?)
+            };
+            sockets.push((name, socket));
+        }
+        Ok(Activated { sockets })
+    }
+
+    /// Removes and returns the next socket called `name`.
+    pub fn take(&mut self, name: &str) -> Result<Socket, anyhow::Error> {
+        match self.sockets.iter().position(|(x, _)| x == name) {
+            Some(index) => Ok(self.sockets.remove(index).1),
+            None => Err(anyhow!(
+                "systemd: no socket named {:?} was passed (have {:?})",
+                name,
+                self.sockets.iter().map(|(x, _)| x).collect::<Vec<_>>()
+            )),
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use crate::dsn::{self, Outcome};
+use crate::envelope::Parameters;
+use crate::mail_sink::{Delivery, DeliveryError, MailSink};
+use crate::smtp_server::{Addr, MailData};
+use async_trait::async_trait;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+
+const IDLE_POLL_SECS: u64 = 60;
+/// Lifetime of a Lark user access token. The spool cannot refresh one taken from an SMTP session.
+const SESSION_TOKEN_SECS: u64 = 7200;
+
+#[derive(Deserialize, Serialize)]
+struct SpoolEntry {
+    from: Addr,
+    to: Vec<Addr>,
+    #[serde(default)]
+    mail_params: Parameters,
+    #[serde(default)]
+    rcpt_params: Vec<Parameters>,
+    subject: String,
+    #[serde(default)]
+    access_token: Option<String>,
+    /// When `access_token` expires at the latest, counted from when the message was queued.
+    #[serde(default)]
+    token_expires: Option<u64>,
+    attempts: u32,
+    next_attempt: u64,
+    last_error: Option<String>,
+    /// This entry is itself a delivery status notification and never triggers another one.
+    #[serde(default)]
+    report: bool,
+    /// When the message was queued.
+    #[serde(default = "unix_now")]
+    queued_at: u64,
+    /// Recipients that asked with `NOTIFY=DELAY` have been told about the delay.
+    #[serde(default)]
+    delay_notified: bool,
+}
+
+/// On-disk outbound queue in front of another [`MailSink`].
+///
+/// Every message is written to `queue/` before `deliver` returns, and a
+/// background worker hands it to the inner sink with exponential backoff.
+/// Messages that fail permanently, or still fail after `max_attempts`, are
+/// moved to `dead/`. Final failures, and successes and delays the client asked about
+/// with `NOTIFY`, are reported back to the envelope sender as a DSN queued behind them.
+pub struct Spool {
+    host: String,
+    retry: Retry,
+    queue_dir: PathBuf,
+    dead_dir: PathBuf,
+    sink: Arc<dyn MailSink>,
+    wakeup: Notify,
+    counter: AtomicU64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// How a [`Spool`] retries transient failures.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// Wait after the first failure, doubled after each further one.
+    pub base_secs: u64,
+    pub max_secs: u64,
+    /// Attempts after which a message is given up on.
+    pub max_attempts: u32,
+    /// Time in the queue after which recipients that asked with `NOTIFY=DELAY` hear about the delay.
+    pub delay_notice_secs: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            base_secs: 60,
+            max_secs: 3600 * 6,
+            max_attempts: 10,
+            delay_notice_secs: 3600 * 4,
+        }
+    }
+}
+
+impl Retry {
+    /// Seconds to wait before the next attempt after `attempts` failed ones.
+    pub fn delay(&self, attempts: u32) -> u64 {
+        self.base_secs
+            .saturating_mul(1 << attempts.saturating_sub(1).min(32))
+            .min(self.max_secs)
+    }
+}
+
+fn session_token_expired() -> DeliveryError {
+    DeliveryError::new(
+        554,
+        "5.7.0",
+        "Access token of the submitting session expired before the message could be delivered",
+    )
+}
+
+/// Writes `contents` through a temporary file, readable by the owner only since entries may hold access tokens.
+async fn write_durable(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+impl Spool {
+    /// `host` names this server as the Reporting-MTA in delivery status notifications.
+    pub fn new(
+        dir: impl AsRef<Path>,
+        host: &str,
+        sink: Arc<dyn MailSink>,
+    ) -> Result<Arc<Self>, anyhow::Error> {
+        Spool::with_retry(dir, host, sink, Retry::default())
+    }
+
+    pub fn with_retry(
+        dir: impl AsRef<Path>,
+        host: &str,
+        sink: Arc<dyn MailSink>,
+        retry: Retry,
+    ) -> Result<Arc<Self>, anyhow::Error> {
+        let queue_dir = dir.as_ref().join("queue");
+        let dead_dir = dir.as_ref().join("dead");
+        std::fs::create_dir_all(&queue_dir)?;
+        std::fs::create_dir_all(&dead_dir)?;
+
+        let spool = Arc::new(Spool {
+            host: host.to_string(),
+            retry,
+            queue_dir,
+            dead_dir,
+            sink,
+            wakeup: Notify::new(),
+            counter: AtomicU64::new(0),
+        });
+
+        let spool_clone = spool.clone();
+        tokio::spawn(async move {
+            spool_clone.worker().await;
+        });
+
+        Ok(spool)
+    }
+
+    fn entry_path(&self, id: &str) -> PathBuf {
+        self.queue_dir.join(format!("{}.json", id))
+    }
+
+    fn body_path(&self, id: &str) -> PathBuf {
+        self.queue_dir.join(format!("{}.eml", id))
+    }
+
+    async fn enqueue(&self, mail_data: MailData, report: bool) -> Result<String, anyhow::Error> {
+        let id = format!(
+            "{}-{}",
+            Local::now().format("%Y%m%d%H%M%S%f"),
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let entry = SpoolEntry {
+            from: mail_data.from,
+            to: mail_data.to,
+            mail_params: mail_data.mail_params,
+            rcpt_params: mail_data.rcpt_params,
+            subject: mail_data.subject,
+            token_expires: mail_data
+                .access_token
+                .as_ref()
+                .map(|_| unix_now() + SESSION_TOKEN_SECS),
+            access_token: mail_data.access_token,
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+            report,
+            queued_at: unix_now(),
+            delay_notified: false,
+        };
+
+        // The body goes first: an entry only counts as queued once its JSON exists.
+        write_durable(&self.body_path(&id), &mail_data.body).await?;
+        write_durable(&self.entry_path(&id), &serde_json::to_vec(&entry)?).await?;
+        self.wakeup.notify_one();
+        Ok(id)
+    }
+
+    async fn worker(&self) {
+        loop {
+            let wait = match self.flush().await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    println!("{}  spool: {}", Local::now().format("%Y/%m/%d %H:%M:%S"), e);
+                    IDLE_POLL_SECS
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(wait)) => {}
+                _ = self.wakeup.notified() => {}
+            }
+        }
+    }
+
+    /// Attempts every due entry and returns the number of seconds until the next one.
+    async fn flush(&self) -> Result<u64, anyhow::Error> {
+        let mut ids = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.queue_dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|x| x.to_str()) {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+
+        let mut wait = IDLE_POLL_SECS;
+        for id in ids {
+            match self.attempt(&id).await {
+                Ok(Some(next_attempt)) => {
+                    wait = wait.min(next_attempt.saturating_sub(unix_now()).max(1))
+                }
+                Ok(None) => {}
+                Err(e) => println!(
+                    "{}  spool {}: {}",
+                    Local::now().format("%Y/%m/%d %H:%M:%S"),
+                    id,
+                    e
+                ),
+            }
+        }
+        Ok(wait)
+    }
+
+    /// Delivers one entry if it is due. Returns the next attempt time when it stays queued.
+    async fn attempt(&self, id: &str) -> Result<Option<u64>, anyhow::Error> {
+        let mut entry: SpoolEntry =
+            serde_json::from_slice(&tokio::fs::read(self.entry_path(id)).await?)?;
+        if entry.next_attempt > unix_now() {
+            return Ok(Some(entry.next_attempt));
+        }
+
+        let body = tokio::fs::read(self.body_path(id)).await?;
+        let mail_to = entry
+            .to
+            .iter()
+            .map(|x| x.mail_address.clone())
+            .collect::<Vec<_>>();
+        let mail_data = MailData {
+            from: entry.from.clone(),
+            to: entry.to.clone(),
+            mail_params: entry.mail_params.clone(),
+            rcpt_params: entry.rcpt_params.clone(),
+            subject: entry.subject.clone(),
+            body,
+            access_token: entry.access_token.clone(),
+        };
+
+        let result = match entry.token_expires {
+            Some(expires) if expires <= unix_now() => Err(session_token_expired().into()),
+            _ => self.sink.deliver(mail_data.clone()).await,
+        };
+        // Nothing here can renew a session token, so retrying after it is rejected is pointless.
+        let result = match result {
+            Err(e)
+                if entry.access_token.is_some()
+                    && e.downcast_ref::<DeliveryError>()
+                        .is_some_and(|e| e.token_rejected) =>
+            {
+                Err(session_token_expired().into())
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) => {
+                println!(
+                    "{}  to: {:?} send success",
+                    Local::now().format("%Y/%m/%d %H:%M:%S"),
+                    &mail_to
+                );
+                tokio::fs::remove_file(self.entry_path(id)).await?;
+                tokio::fs::remove_file(self.body_path(id)).await?;
+                if !entry.report {
+                    self.notify(&mail_data, Outcome::Relayed).await;
+                }
+                Ok(None)
+            }
+            Err(e) => {
+                entry.attempts += 1;
+                entry.last_error = Some(e.to_string());
+                let permanent = e
+                    .downcast_ref::<DeliveryError>()
+                    .is_some_and(|e| e.is_permanent());
+                if permanent || entry.attempts >= self.retry.max_attempts {
+                    println!(
+                        "{}  to:{:?}  {}  (giving up after {} attempts, moved to dead letters)",
+                        Local::now().format("%Y/%m/%d %H:%M:%S"),
+                        &mail_to,
+                        e,
+                        entry.attempts
+                    );
+                    self.bury(id, &mut entry).await?;
+                    if !entry.report {
+                        let failure = match e.downcast_ref::<DeliveryError>() {
+                            Some(e) if e.is_permanent() => e.clone(),
+                            _ => DeliveryError::new(
+                                554,
+                                "5.4.7",
+                                format!(
+                                    "Delivery time expired after {} attempts: {}",
+                                    entry.attempts, e
+                                ),
+                            ),
+                        };
+                        self.notify(&mail_data, Outcome::Failed(failure)).await;
+                    }
+                    return Ok(None);
+                }
+
+                entry.next_attempt = unix_now() + self.retry.delay(entry.attempts);
+                println!(
+                    "{}  to:{:?}  {}  (attempt {}, retrying in {}s)",
+                    Local::now().format("%Y/%m/%d %H:%M:%S"),
+                    &mail_to,
+                    e,
+                    entry.attempts,
+                    self.retry.delay(entry.attempts)
+                );
+                let delay_notice = !entry.report
+                    && !entry.delay_notified
+                    && unix_now() >= entry.queued_at + self.retry.delay_notice_secs;
+                entry.delay_notified |= delay_notice;
+                write_durable(&self.entry_path(id), &serde_json::to_vec(&entry)?).await?;
+                if delay_notice {
+                    let delay = match e.downcast_ref::<DeliveryError>() {
+                        Some(e) => e.clone(),
+                        None => DeliveryError::new(451, "4.3.0", e.to_string()),
+                    };
+                    self.notify(&mail_data, Outcome::Delayed(delay)).await;
+                }
+                Ok(Some(entry.next_attempt))
+            }
+        }
+    }
+
+    /// Queues a DSN for `mail_data` if its recipients asked for one. Failures are only logged.
+    async fn notify(&self, mail_data: &MailData, outcome: Outcome) {
+        let report = match dsn::report(&self.host, mail_data, &outcome) {
+            Some(report) => report,
+            None => return,
+        };
+        if let Err(e) = self.enqueue(report, true).await {
+            println!(
+                "{}  spool: unable to queue delivery status notification to {}: {}",
+                Local::now().format("%Y/%m/%d %H:%M:%S"),
+                mail_data.from.mail_address,
+                e
+            );
+        }
+    }
+
+    /// Moves an entry to `dead/`, dropping its access token.
+    async fn bury(&self, id: &str, entry: &mut SpoolEntry) -> Result<(), anyhow::Error> {
+        entry.access_token = None;
+        tokio::fs::rename(
+            self.body_path(id),
+            self.dead_dir.join(format!("{}.eml", id)),
+        )
+        .await?;
+        write_durable(
+            &self.dead_dir.join(format!("{}.json", id)),
+            &serde_json::to_vec(entry)?,
+        )
+        .await?;
+        tokio::fs::remove_file(self.entry_path(id)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MailSink for Spool {
+    async fn deliver(&self, mail_data: MailData) -> Result<Delivery, anyhow::Error> {
+        let id = self.enqueue(mail_data, false).await?;
+        Ok(Delivery {
+            message_id: Some(id),
+        })
+    }
+
+    fn reports_status(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::anyhow;
+use chrono::Local;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use x509_parser::extensions::GeneralName;
+
+/// How often the PEM files are checked for changes.
+pub const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KeyPair {
+    pub cert: String,
+    pub key: String,
+}
+
+/// The `tls` section of `config.json`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// Served when the client sends no SNI name, or one without an entry in `sni`.
+    #[serde(flatten)]
+    pub default: KeyPair,
+    /// Certificates by hostname; `*.example.com` matches any single label.
+    #[serde(default)]
+    pub sni: BTreeMap<String, KeyPair>,
+    /// PEM bundle of CAs whose client certificates are accepted; clients may still connect without one.
+    pub client_ca: Option<String>,
+}
+
+#[derive(Debug)]
+struct CertSet {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    /// Every PEM file with its modification time when it was loaded.
+    files: Vec<(String, Option<SystemTime>)>,
+}
+
+/// Picks a certificate by SNI name and swaps in new ones when the PEM files change.
+///
+/// A reload that fails is logged and the certificates already loaded stay in use.
+#[derive(Debug)]
+pub struct CertResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    certs: RwLock<Arc<CertSet>>,
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+fn load_key_pair(
+    provider: &CryptoProvider,
+    pair: &KeyPair,
+) -> Result<Arc<CertifiedKey>, anyhow::Error> {
+    let certs = CertificateDer::pem_file_iter(&pair.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("{}: unable to read certificates: {:?}", pair.cert, e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("{}: no certificates found", pair.cert));
+    }
+    let key = PrivateKeyDer::from_pem_file(&pair.key)
+        .map_err(|e| anyhow!("{}: unable to read private key: {:?}", pair.key, e))?;
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| anyhow!("{}: {}", pair.key, e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified
+        .keys_match()
+        .map_err(|e| anyhow!("{} does not match {}: {}", pair.key, pair.cert, e))?;
+    Ok(Arc::new(certified))
+}
+
+fn load(provider: &CryptoProvider, config: &TlsConfig) -> Result<CertSet, anyhow::Error> {
+    let mut files = Vec::new();
+    for pair in std::iter::once(&config.default).chain(config.sni.values()) {
+        files.push((pair.cert.clone(), modified(&pair.cert)));
+        files.push((pair.key.clone(), modified(&pair.key)));
+    }
+    let default = load_key_pair(provider, &config.default)?;
+    let mut by_name = HashMap::new();
+    for (name, pair) in &config.sni {
+        by_name.insert(name.to_lowercase(), load_key_pair(provider, pair)?);
+    }
+    Ok(CertSet {
+        default,
+        by_name,
+        files,
+    })
+}
+
+impl CertResolver {
+    /// Loads every certificate in `config`; any unreadable or mismatched file is an error.
+    pub fn new(
+        config: TlsConfig,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<Self>, anyhow::Error> {
+        let certs = load(&provider, &config)?;
+        Ok(Arc::new(CertResolver {
+            config,
+            provider,
+            certs: RwLock::new(Arc::new(certs)),
+        }))
+    }
+
+    /// Loads the PEM files again, keeping the current certificates if that fails.
+    pub fn reload(&self) -> Result<(), anyhow::Error> {
+        let certs = load(&self.provider, &self.config)?;
+        *self.certs.write().unwrap() = Arc::new(certs);
+        Ok(())
+    }
+
+    /// Whether any PEM file was modified, created or removed since the last successful load.
+    pub fn changed(&self) -> bool {
+        let certs = self.certs.read().unwrap().clone();
+        certs
+            .files
+            .iter()
+            .any(|(path, loaded)| modified(path) != *loaded)
+    }
+
+    /// Reloads on SIGHUP, and whenever the PEM files change on disk.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+            loop {
+                #[cfg(unix)]
+                let forced = tokio::select! {
+                    _ = tokio::time::sleep(interval) => false,
+                    Some(_) = async { hangup.as_mut()?.recv().await } => true,
+                };
+                #[cfg(not(unix))]
+                let forced = {
+                    tokio::time::sleep(interval).await;
+                    false
+                };
+                if !forced && !resolver.changed() {
+                    continue;
+                }
+                let result = match resolver.reload() {
+                    Ok(_) => "reloaded".to_string(),
+                    Err(e) => format!("reload failed, keeping the current certificates: {}", e),
+                };
+                println!(
+                    "{}  tls: {}",
+                    Local::now().format("%Y/%m/%d %H:%M:%S"),
+                    result
+                );
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap().clone();
+        let name = match client_hello.server_name() {
+            Some(name) => name.to_lowercase(),
+            None => return Some(certs.default.clone()),
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        certs
+            .by_name
+            .get(&name)
+            .or_else(|| wildcard.and_then(|x| certs.by_name.get(&x)))
+            .or(Some(&certs.default))
+            .cloned()
+    }
+}
+
+fn client_verifier(
+    provider: &Arc<CryptoProvider>,
+    client_ca: &str,
+) -> Result<Arc<dyn ClientCertVerifier>, anyhow::Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(client_ca)
+        .map_err(|e| anyhow!("{}: unable to read certificates: {:?}", client_ca, e))?
+    {
+        let cert =
+            cert.map_err(|e| anyhow!("{}: unable to read certificates: {:?}", client_ca, e))?;
+        roots
+            .add(cert)
+            .map_err(|e| anyhow!("{}: {}", client_ca, e))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("{}: no certificates found", client_ca));
+    }
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .allow_unauthenticated()
+        .build()
+        .map_err(|e| anyhow!("{}: {}", client_ca, e))
+}
+
+/// Names a client certificate can be matched on: subject common names and
+/// DNS, email and URI subjectAltNames.
+pub fn certificate_names(cert: &CertificateDer) -> Vec<String> {
+    let cert = match x509_parser::parse_x509_certificate(cert) {
+        Ok((_, cert)) => cert,
+        Err(_) => return Vec::new(),
+    };
+    let mut names = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|x| x.as_str().ok())
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(x) | GeneralName::RFC822Name(x) | GeneralName::URI(x) => {
+                    names.push(x.to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+/// Builds a server config that serves the certificates in `config` and can be reloaded through the resolver.
+pub fn server_config(
+    config: TlsConfig,
+) -> Result<(Arc<rustls::ServerConfig>, Arc<CertResolver>), anyhow::Error> {
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+    let verifier = match &config.client_ca {
+        Some(client_ca) => client_verifier(&provider, client_ca)?,
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+    let resolver = CertResolver::new(config, provider.clone())?;
+    let server_config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(resolver.clone());
+    Ok((Arc::new(server_config), resolver))
+}
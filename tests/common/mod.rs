@@ -0,0 +1,340 @@
+//! Test support: an in-process stand-in for the Lark Open API and SMTP session helpers.
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use smtp2larkapi::mail_sink::{Delivery, MailSink};
+use smtp2larkapi::sasl::Registry;
+use smtp2larkapi::smtp_server::{serve, Account, MailConfig, MailData, DEFAULT_MAX_SIZE};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct SentMail {
+    pub mailbox: String,
+    pub access_token: String,
+    pub body: Value,
+}
+
+struct MockState {
+    sent: Vec<SentMail>,
+    injected: VecDeque<(u16, Value)>,
+    access_tokens: HashSet<String>,
+    refresh_tokens: HashMap<String, String>,
+    token_lifetime: u64,
+    issued: u64,
+    refresh_count: u64,
+}
+
+/// Emulates the auth endpoints and `messages/send`, recording every sent message.
+///
+/// Authorization codes double as user identities: exchanging code `alice`
+/// yields access tokens named `user-alice-<n>`.
+pub struct MockLark {
+    pub base_url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockLark {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState {
+            sent: Vec::new(),
+            injected: VecDeque::new(),
+            access_tokens: HashSet::new(),
+            refresh_tokens: HashMap::new(),
+            token_lifetime: 7200,
+            issued: 0,
+            refresh_count: 0,
+        }));
+
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let state = state_clone.clone();
+                tokio::spawn(async move {
+                    let _ = handle(stream, state).await;
+                });
+            }
+        });
+
+        MockLark { base_url, state }
+    }
+
+    pub fn sent(&self) -> Vec<SentMail> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Makes the next `messages/send` call fail with the given HTTP status and Lark code.
+    pub fn inject_send_error(&self, status: u16, code: i64, msg: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .injected
+            .push_back((status, json!({ "code": code, "msg": msg })));
+    }
+
+    /// Sets `expires_in` for access tokens issued from now on.
+    pub fn set_token_lifetime(&self, seconds: u64) {
+        self.state.lock().unwrap().token_lifetime = seconds;
+    }
+
+    /// Invalidates every access token issued so far, as if they had been revoked.
+    pub fn revoke_access_tokens(&self) {
+        self.state.lock().unwrap().access_tokens.clear();
+    }
+
+    /// Issues a user access token for `identity`, whose mailbox is `<identity>@example.com`.
+    pub fn issue_access_token(&self, identity: &str) -> String {
+        let issued = issue_user_token(&mut self.state.lock().unwrap(), identity);
+        issued["data"]["access_token"].as_str().unwrap().to_string()
+    }
+
+    pub fn refresh_count(&self) -> u64 {
+        self.state.lock().unwrap().refresh_count
+    }
+
+    /// Writes an `app_info.json` pointing at this mock into a fresh directory.
+    pub fn data_dir(&self, name: &str, mut app_info: Value) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("smtp2larkapi-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        app_info["app_id"] = json!("cli_test");
+        app_info["app_secret"] = json!("secret");
+        app_info["api_base"] = json!(self.base_url);
+        std::fs::write(dir.join("app_info.json"), app_info.to_string()).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+}
+
+fn issue_user_token(state: &mut MockState, identity: &str) -> Value {
+    state.issued += 1;
+    let access_token = format!("user-{}-{}", identity, state.issued);
+    let refresh_token = format!("refresh-{}-{}", identity, state.issued);
+    state.access_tokens.insert(access_token.clone());
+    state
+        .refresh_tokens
+        .insert(refresh_token.clone(), identity.to_string());
+    json!({
+        "code": 0,
+        "message": "success",
+        "data": {
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "expires_in": state.token_lifetime,
+            "refresh_expires_in": 2592000,
+        }
+    })
+}
+
+fn route(state: &Mutex<MockState>, path: &str, auth: &str, body: &Value) -> (u16, Value) {
+    let mut state = state.lock().unwrap();
+    match path {
+        "/open-apis/auth/v3/app_access_token/internal" => (
+            200,
+            json!({ "code": 0, "msg": "ok", "app_access_token": "app-token", "expire": 7200 }),
+        ),
+        "/open-apis/authen/v1/oidc/access_token" => match body["code"].as_str() {
+            Some(code) if !code.is_empty() => (200, issue_user_token(&mut state, code)),
+            _ => (400, json!({ "code": 20003, "message": "invalid code" })),
+        },
+        "/open-apis/authen/v1/oidc/refresh_access_token" => {
+            let identity = body["refresh_token"]
+                .as_str()
+                .and_then(|token| state.refresh_tokens.get(token).cloned());
+            match identity {
+                Some(identity) => {
+                    state.refresh_count += 1;
+                    (200, issue_user_token(&mut state, &identity))
+                }
+                None => (
+                    400,
+                    json!({ "code": 20026, "message": "refresh token invalid" }),
+                ),
+            }
+        }
+        "/open-apis/authen/v1/user_info" => {
+            let access_token = auth.trim_start_matches("Bearer ");
+            if !state.access_tokens.contains(access_token) {
+                return (
+                    401,
+                    json!({ "code": 99991668, "msg": "invalid access token" }),
+                );
+            }
+            let identity = access_token
+                .trim_start_matches("user-")
+                .rsplit_once('-')
+                .map(|(identity, _)| identity)
+                .unwrap_or_default();
+            (
+                200,
+                json!({
+                    "code": 0,
+                    "msg": "success",
+                    "data": {
+                        "name": identity,
+                        "email": format!("{}@example.com", identity),
+                        "enterprise_email": "",
+                    }
+                }),
+            )
+        }
+        _ if path.starts_with("/open-apis/mail/v1/user_mailboxes/")
+            && path.ends_with("/messages/send") =>
+        {
+            if let Some(injected) = state.injected.pop_front() {
+                return injected;
+            }
+            let access_token = auth.trim_start_matches("Bearer ").to_string();
+            if !state.access_tokens.contains(&access_token) {
+                return (
+                    400,
+                    json!({ "code": 99991677, "msg": "user access token expired" }),
+                );
+            }
+            let mailbox = path
+                .trim_start_matches("/open-apis/mail/v1/user_mailboxes/")
+                .trim_end_matches("/messages/send")
+                .to_string();
+            state.sent.push(SentMail {
+                mailbox,
+                access_token,
+                body: body.clone(),
+            });
+            let message_id = format!("msg-{}", state.sent.len());
+            (
+                200,
+                json!({ "code": 0, "msg": "success", "data": { "message_id": message_id } }),
+            )
+        }
+        _ => (404, json!({ "code": -1, "msg": "not found" })),
+    }
+}
+
+async fn handle(stream: TcpStream, state: Arc<Mutex<MockState>>) -> Result<(), anyhow::Error> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_string();
+
+    let mut content_length = 0;
+    let mut auth = String::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse()?,
+                "authorization" => auth = value.trim().to_string(),
+                _ => {}
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    let (status, response) = route(&state, &path, &auth, &body);
+    let response = response.to_string();
+    reader
+        .write_all(
+            format!(
+                "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            )
+            .as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Sink that keeps every delivered message in memory.
+#[derive(Default)]
+pub struct MemorySink {
+    received: Mutex<Vec<MailData>>,
+}
+
+impl MemorySink {
+    pub fn take(&self) -> Vec<MailData> {
+        std::mem::take(&mut *self.received.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl MailSink for MemorySink {
+    async fn deliver(&self, mail_data: MailData) -> Result<Delivery, anyhow::Error> {
+        self.received.lock().unwrap().push(mail_data);
+        Ok(Delivery::default())
+    }
+}
+
+pub fn mail_config() -> Arc<MailConfig> {
+    Arc::new(MailConfig {
+        accounts: vec![Account {
+            user: "relay".to_string(),
+            passwd: "secret".to_string(),
+            ..Default::default()
+        }],
+        host: "smtp.test".to_string(),
+        default_name: String::new(),
+        max_size: DEFAULT_MAX_SIZE,
+        sasl: Registry::default(),
+        tokens: None,
+        tls_type: None,
+        tls_cert: None,
+    })
+}
+
+/// Feeds `script` to an SMTP session over an in-memory stream and returns everything the server wrote.
+pub async fn smtp_session(
+    config: Arc<MailConfig>,
+    sink: Arc<dyn MailSink>,
+    script: impl AsRef<[u8]>,
+) -> String {
+    let (mut client, server) = tokio::io::duplex(1 << 20);
+    let session = tokio::spawn(async move { serve(server, config, sink).await });
+    client.write_all(script.as_ref()).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    let mut output = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), client.read_to_end(&mut output))
+        .await
+        .expect("SMTP session did not finish")
+        .unwrap();
+    let _ = session.await;
+    String::from_utf8_lossy(&output).to_string()
+}
+
+/// A complete authenticated transaction from `from` to `to`, followed by QUIT.
+pub fn send_script(from: &str, to: &str, subject: &str) -> String {
+    format!(
+        "EHLO client.test\r\n\
+         AUTH PLAIN {}\r\n\
+         MAIL FROM:<{from}>\r\n\
+         RCPT TO:<{to}>\r\n\
+         DATA\r\n\
+         From: Sender <{from}>\r\n\
+         To: <{to}>\r\n\
+         Subject: {subject}\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         \r\n\
+         <p>{subject}</p>\r\n\
+         .\r\n\
+         QUIT\r\n",
+        smtp2larkapi::smtp_server::plain_encode("relay", "secret"),
+    )
+}
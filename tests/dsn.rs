@@ -0,0 +1,221 @@
+mod common;
+
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use common::{mail_config, smtp_session, MockLark, SentMail};
+use serde_json::json;
+use smtp2larkapi::lark_api_mail::LarkMail;
+use smtp2larkapi::smtp_server::plain_encode;
+use smtp2larkapi::spool::{Retry, Spool};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn script(mail_params: &str, rcpt_params: &str) -> String {
+    format!(
+        "EHLO client.test\r\n\
+         AUTH PLAIN {}\r\n\
+         MAIL FROM:<alice@example.com>{mail_params}\r\n\
+         RCPT TO:<bob@example.com>{rcpt_params}\r\n\
+         DATA\r\n\
+         From: <alice@example.com>\r\n\
+         Subject: Report me\r\n\
+         \r\n\
+         secret body\r\n\
+         .\r\n\
+         QUIT\r\n",
+        plain_encode("relay", "secret"),
+    )
+}
+
+async fn wait_for_sent(mock: &MockLark, count: usize) -> Vec<SentMail> {
+    for _ in 0..100 {
+        let sent = mock.sent();
+        if sent.len() >= count {
+            return sent;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("expected {} sent messages, got {:?}", count, mock.sent());
+}
+
+fn attachment(sent: &SentMail, filename: &str) -> String {
+    let attachment = sent.body["attachments"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["filename"] == filename)
+        .unwrap_or_else(|| panic!("no {} in {}", filename, sent.body));
+    let body = URL_SAFE
+        .decode(attachment["body"].as_str().unwrap())
+        .unwrap();
+    String::from_utf8(body).unwrap()
+}
+
+async fn spool(mock: &MockLark, name: &str) -> Arc<Spool> {
+    spool_with_retry(mock, name, Retry::default()).await
+}
+
+async fn spool_with_retry(mock: &MockLark, name: &str, retry: Retry) -> Arc<Spool> {
+    let dir = mock.data_dir(name, json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    Spool::with_retry(format!("{}/spool", dir), "smtp.test", lark, retry).unwrap()
+}
+
+#[tokio::test]
+async fn permanent_failure_bounces_to_sender() {
+    let mock = MockLark::start().await;
+    let spool = spool(&mock, "dsn-failure").await;
+    mock.inject_send_error(400, 99991672, "app scope missing");
+
+    let output = smtp_session(
+        mail_config(),
+        spool,
+        script(" RET=FULL ENVID=env+2B1", " ORCPT=rfc822;Bob+40example.com"),
+    )
+    .await;
+    assert!(output.contains("250-DSN\r\n"), "{}", output);
+    assert!(output.contains("250 2.0.0 OK queued as "), "{}", output);
+
+    let sent = wait_for_sent(&mock, 1).await;
+    let report = &sent[0];
+    assert_eq!(report.mailbox, "alice@example.com");
+    assert_eq!(report.body["to"][0]["mail_address"], "alice@example.com");
+    assert_eq!(
+        report.body["subject"],
+        "Delivery Status Notification (Failure)"
+    );
+    let html = report.body["body_html"].as_str().unwrap();
+    assert!(html.contains("bob@example.com"), "{}", html);
+    assert!(html.contains("550 5.7.1 app scope missing"), "{}", html);
+
+    let status = attachment(report, "delivery-status.txt");
+    assert!(
+        status.contains("Reporting-MTA: dns; smtp.test"),
+        "{}",
+        status
+    );
+    assert!(status.contains("Original-Envelope-Id: env+1"), "{}", status);
+    assert!(
+        status.contains("Original-Recipient: rfc822; Bob@example.com"),
+        "{}",
+        status
+    );
+    assert!(status.contains("Action: failed"), "{}", status);
+    assert!(status.contains("Status: 5.7.1"), "{}", status);
+    assert!(attachment(report, "original.eml").contains("secret body"));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mock.sent().len(), 1);
+}
+
+#[tokio::test]
+async fn success_is_reported_only_when_requested() {
+    let mock = MockLark::start().await;
+    let spool = spool(&mock, "dsn-success").await;
+
+    smtp_session(mail_config(), spool.clone(), script("", "")).await;
+    wait_for_sent(&mock, 1).await;
+    smtp_session(mail_config(), spool, script("", " NOTIFY=SUCCESS,FAILURE")).await;
+
+    let sent = wait_for_sent(&mock, 3).await;
+    assert_eq!(sent[1].body["subject"], "Report me");
+    let report = &sent[2];
+    assert_eq!(
+        report.body["subject"],
+        "Delivery Status Notification (Relayed)"
+    );
+    let status = attachment(report, "delivery-status.txt");
+    assert!(status.contains("Action: relayed"), "{}", status);
+    assert!(status.contains("Status: 2.0.0"), "{}", status);
+    let headers = attachment(report, "original-headers.txt");
+    assert!(headers.contains("Subject: Report me"), "{}", headers);
+    assert!(!headers.contains("secret body"), "{}", headers);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mock.sent().len(), 3);
+}
+
+#[tokio::test]
+async fn delay_is_reported_once_queued_long_enough() {
+    // A short hiccup right after submission is not worth a notice.
+    let mock = MockLark::start().await;
+    let spool = spool(&mock, "dsn-no-delay").await;
+    mock.inject_send_error(400, 99991400, "request trigger frequency limit");
+    smtp_session(mail_config(), spool, script("", " NOTIFY=DELAY,FAILURE")).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(mock.sent().is_empty());
+
+    let mock = MockLark::start().await;
+    let retry = Retry {
+        delay_notice_secs: 0,
+        ..Default::default()
+    };
+    let spool = spool_with_retry(&mock, "dsn-delay", retry).await;
+    mock.inject_send_error(400, 99991400, "request trigger frequency limit");
+    smtp_session(mail_config(), spool, script("", " NOTIFY=DELAY,FAILURE")).await;
+
+    let sent = wait_for_sent(&mock, 1).await;
+    let report = &sent[0];
+    assert_eq!(
+        report.body["subject"],
+        "Delivery Status Notification (Delay)"
+    );
+    let status = attachment(report, "delivery-status.txt");
+    assert!(status.contains("Action: delayed"), "{}", status);
+    assert!(status.contains("Status: 4.4.5"), "{}", status);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mock.sent().len(), 1);
+}
+
+#[tokio::test]
+async fn rejects_invalid_dsn_parameters() {
+    let mock = MockLark::start().await;
+    let spool = spool(&mock, "dsn-params").await;
+    let output = smtp_session(
+        mail_config(),
+        spool,
+        "EHLO client.test\r\n\
+         AUTH PLAIN AHJlbGF5AHNlY3JldA==\r\n\
+         MAIL FROM:<alice@example.com> RET=BODY\r\n\
+         MAIL FROM:<alice@example.com> ENVID=a+0D+0ABcc:+20eve@example.com\r\n\
+         MAIL FROM:<alice@example.com> RET=HDRS\r\n\
+         RCPT TO:<bob@example.com> NOTIFY=NEVER,SUCCESS\r\n\
+         RCPT TO:<bob@example.com> ORCPT=bob@example.com\r\n\
+         RCPT TO:<bob@example.com> ORCPT=rfc822;bob@example.com+0D+0AX-Injected:+201\r\n\
+         RCPT TO:<bob@example.com> NOTIFY=NEVER\r\n\
+         QUIT\r\n",
+    )
+    .await;
+    let replies = output.lines().collect::<Vec<_>>();
+    assert!(
+        replies.contains(&"501 5.5.4 RET must be FULL or HDRS"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"501 5.5.4 Invalid ENVID parameter"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"501 5.5.4 Invalid NOTIFY parameter"),
+        "{}",
+        output
+    );
+    assert_eq!(
+        replies
+            .iter()
+            .filter(|x| **x == "501 5.5.4 Invalid ORCPT parameter")
+            .count(),
+        2,
+        "{}",
+        output
+    );
+    assert_eq!(
+        replies.iter().filter(|x| x.starts_with("250 2.1.")).count(),
+        2,
+        "{}",
+        output
+    );
+}
@@ -0,0 +1,107 @@
+use smtp2larkapi::envelope::{parse_mail_from, parse_rcpt_to};
+
+fn rejected(result: Result<impl std::fmt::Debug, smtp2larkapi::reply::Reply>) -> String {
+    result.unwrap_err().to_string()
+}
+
+#[test]
+fn parses_plain_paths_and_parameters() {
+    let arg = parse_mail_from("MAIL FROM:<alice@example.com> SIZE=1000 BODY=8bitmime\r\n").unwrap();
+    assert_eq!(arg.address, "alice@example.com");
+    assert_eq!(arg.params.get("size"), Some("1000"));
+    assert_eq!(arg.params.get("BODY"), Some("8bitmime"));
+    assert_eq!(arg.params.keywords().collect::<Vec<_>>(), ["BODY", "SIZE"]);
+
+    let arg = parse_mail_from("mail from: <alice@example.com> SMTPUTF8 \r\n").unwrap();
+    assert_eq!(arg.address, "alice@example.com");
+    assert!(arg.params.contains("smtputf8"));
+    assert_eq!(arg.params.get("SMTPUTF8"), None);
+
+    let arg = parse_rcpt_to(
+        "RCPT TO:<bob@example.com> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;bob+2B@example.com\r\n",
+    )
+    .unwrap();
+    assert_eq!(arg.address, "bob@example.com");
+    assert_eq!(arg.params.get("NOTIFY"), Some("SUCCESS,FAILURE"));
+    assert_eq!(arg.params.get("ORCPT"), Some("rfc822;bob+2B@example.com"));
+}
+
+#[test]
+fn handles_null_path_and_postmaster() {
+    let arg = parse_mail_from("MAIL FROM:<>\r\n").unwrap();
+    assert_eq!(arg.address, "");
+    assert!(arg.params.is_empty());
+
+    assert!(rejected(parse_rcpt_to("RCPT TO:<>\r\n")).starts_with("501 5.1.3 "));
+    assert_eq!(
+        parse_rcpt_to("RCPT TO:<Postmaster>\r\n").unwrap().address,
+        "Postmaster"
+    );
+    assert!(parse_mail_from("MAIL FROM:<Postmaster>\r\n").is_err());
+}
+
+#[test]
+fn accepts_quoted_local_parts_routes_and_literals() {
+    let arg = parse_rcpt_to("RCPT TO:<\"john <doe>\\\"x\"@example.com>\r\n").unwrap();
+    assert_eq!(arg.address, "\"john <doe>\\\"x\"@example.com");
+
+    let arg = parse_rcpt_to("RCPT TO:<@a.example,@b.example:carol@example.com>\r\n").unwrap();
+    assert_eq!(arg.address, "carol@example.com");
+
+    for address in [
+        "dave@[192.0.2.1]",
+        "dave@[IPv6:2001:db8::1]",
+        "o'brien+tag@sub.example.com",
+        "用户@例子.广告",
+    ] {
+        let arg = parse_rcpt_to(&format!("RCPT TO:<{}>\r\n", address)).unwrap();
+        assert_eq!(arg.address, address);
+    }
+}
+
+#[test]
+fn rejects_malformed_paths() {
+    for line in [
+        "RCPT TO:bob@example.com",
+        "RCPT TO:<bob@example.com",
+        "RCPT TO:<bob>",
+        "RCPT TO:<bob@>",
+        "RCPT TO:<.bob@example.com>",
+        "RCPT TO:<bob..smith@example.com>",
+        "RCPT TO:<bob smith@example.com>",
+        "RCPT TO:<bob@-example.com>",
+        "RCPT TO:<bob@exa_mple.com>",
+        "RCPT TO:<bob@[300.0.0.1]>",
+        "RCPT TO:<@relay.example,carol@example.com>",
+        "RCPT TO:>bob@example.com<",
+    ] {
+        assert!(
+            rejected(parse_rcpt_to(line)).starts_with("501 5.1.3 "),
+            "{}",
+            line
+        );
+    }
+
+    let local = "a".repeat(65);
+    assert!(parse_rcpt_to(&format!("RCPT TO:<{}@example.com>", local)).is_err());
+    let domain = format!("{}.example", "a".repeat(250));
+    assert!(parse_rcpt_to(&format!("RCPT TO:<bob@{}>", domain)).is_err());
+}
+
+#[test]
+fn rejects_malformed_parameters() {
+    assert!(rejected(parse_mail_from("MAIL TO:<alice@example.com>")).starts_with("501 5.5.4 "));
+    for line in [
+        "MAIL FROM:<alice@example.com>SIZE=10",
+        "MAIL FROM:<alice@example.com> SIZE=",
+        "MAIL FROM:<alice@example.com> -SIZE=10",
+        "MAIL FROM:<alice@example.com> SIZE=1=0",
+        "MAIL FROM:<alice@example.com> SIZE=10 size=20",
+    ] {
+        assert!(
+            rejected(parse_mail_from(line)).starts_with("501 5.5.4 "),
+            "{}",
+            line
+        );
+    }
+}
@@ -0,0 +1,351 @@
+mod common;
+
+use base64::prelude::*;
+use common::{mail_config, send_script, smtp_session, MockLark};
+use serde_json::json;
+use smtp2larkapi::lark_api_mail::LarkMail;
+use smtp2larkapi::sasl::Registry;
+use smtp2larkapi::smtp_server::plain_encode;
+use smtp2larkapi::spool::Spool;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn delivers_smtp_message_through_lark() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("deliver", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+
+    let app_info = std::fs::read_to_string(format!("{}/app_info.json", dir)).unwrap();
+    assert!(!app_info.contains("\"code\""));
+    assert!(std::path::Path::new(&format!("{}/refresh_token.json", dir)).exists());
+
+    let output = smtp_session(
+        mail_config(),
+        lark,
+        &send_script("alice@example.com", "bob@example.com", "Hello"),
+    )
+    .await;
+    assert!(output.contains("235 "), "{}", output);
+    assert!(
+        output.contains("250 2.0.0 OK queued as msg-1"),
+        "{}",
+        output
+    );
+    assert!(output.ends_with("221 2.0.0 Bye\r\n"), "{}", output);
+
+    let sent = mock.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].mailbox, "alice@example.com");
+    assert!(sent[0].access_token.starts_with("user-alice-"));
+    assert_eq!(sent[0].body["subject"], "Hello");
+    assert_eq!(sent[0].body["to"][0]["mail_address"], "bob@example.com");
+    assert!(sent[0].body["body_html"]
+        .as_str()
+        .unwrap()
+        .contains("<p>Hello</p>"));
+}
+
+#[tokio::test]
+async fn lark_errors_map_to_smtp_replies() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("errors", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    let script = send_script("alice@example.com", "bob@example.com", "Hello");
+
+    mock.inject_send_error(400, 99991672, "app scope missing");
+    let output = smtp_session(mail_config(), lark.clone(), &script).await;
+    assert!(output.contains("550 5.7.1 app scope missing"), "{}", output);
+
+    mock.inject_send_error(200, 99991400, "request trigger frequency limit");
+    let output = smtp_session(mail_config(), lark.clone(), &script).await;
+    assert!(output.contains("451 4.4.5 "), "{}", output);
+
+    // Unknown codes are not guessed from their message text.
+    mock.inject_send_error(400, 1234, "frequency exceeded for this address");
+    let output = smtp_session(mail_config(), lark.clone(), &script).await;
+    assert!(output.contains("554 5.0.0 "), "{}", output);
+
+    mock.inject_send_error(413, 1235, "request body too large");
+    let output = smtp_session(mail_config(), lark, &script).await;
+    assert!(output.contains("552 5.3.4 "), "{}", output);
+
+    assert!(mock.sent().is_empty());
+}
+
+#[tokio::test]
+async fn refreshes_expired_access_token_before_sending() {
+    let mock = MockLark::start().await;
+    mock.set_token_lifetime(0);
+    let dir = mock.data_dir("refresh", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    mock.set_token_lifetime(7200);
+
+    let output = smtp_session(
+        mail_config(),
+        lark,
+        &send_script("alice@example.com", "bob@example.com", "Hello"),
+    )
+    .await;
+    assert!(output.contains("250 2.0.0 OK"), "{}", output);
+    assert_eq!(mock.refresh_count(), 1);
+    assert_eq!(mock.sent()[0].access_token, "user-alice-2");
+}
+
+#[tokio::test]
+async fn revoked_token_is_reported_as_transient_failure() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("revoked", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    mock.revoke_access_tokens();
+
+    let output = smtp_session(
+        mail_config(),
+        lark,
+        &send_script("alice@example.com", "bob@example.com", "Hello"),
+    )
+    .await;
+    assert!(output.contains("451 4.7.0 "), "{}", output);
+    assert!(mock.sent().is_empty());
+}
+
+#[tokio::test]
+async fn picks_user_token_by_envelope_sender() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir(
+        "mailboxes",
+        json!({
+            "code": "owner",
+            "mailboxes": [
+                { "address": "alice@example.com", "code": "alice" },
+                { "address": "Carol@example.com", "code": "carol" },
+                { "address": "erin@example.com" },
+            ]
+        }),
+    );
+    // erin has neither a code nor a saved token, which must not stop the others.
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    assert!(
+        std::path::Path::new(&format!("{}/refresh_token/carol@example.com.json", dir)).exists()
+    );
+
+    let output = smtp_session(
+        mail_config(),
+        lark.clone(),
+        &send_script("carol@example.com", "bob@example.com", "From Carol"),
+    )
+    .await;
+    assert!(output.contains("250 2.0.0 OK"), "{}", output);
+
+    // Neither an unlisted sender nor a skipped mailbox falls back to the default token.
+    // Mail from the skipped one is only deferred, so it survives re-authorization.
+    for (sender, reply) in [
+        ("dave@example.com", "550 5.7.1 "),
+        ("erin@example.com", "451 4.7.0 "),
+    ] {
+        let output = smtp_session(
+            mail_config(),
+            lark.clone(),
+            &send_script(sender, "bob@example.com", "Not sent"),
+        )
+        .await;
+        assert!(output.contains(reply), "{}", output);
+    }
+
+    let sent = mock.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].mailbox, "carol@example.com");
+    assert!(sent[0].access_token.starts_with("user-carol-"));
+}
+
+#[tokio::test]
+async fn escapes_quoted_sender_in_mailbox_path() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("quoted", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+
+    let output = smtp_session(
+        mail_config(),
+        lark,
+        &send_script("\"a?b/c\"@example.com", "bob@example.com", "Quoted"),
+    )
+    .await;
+    assert!(
+        output.contains("250 2.0.0 OK queued as msg-1"),
+        "{}",
+        output
+    );
+    assert_eq!(mock.sent()[0].mailbox, "%22a%3Fb%2Fc%22@example.com");
+}
+
+#[tokio::test]
+async fn decodes_8bit_body_in_declared_charset() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("8bit", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+
+    let script = send_script("alice@example.com", "bob@example.com", "Latin")
+        .replace("charset=utf-8", "charset=iso-8859-1")
+        .replace("<p>Latin</p>", "<p>caf#</p>\r\n..");
+    let bytes = script
+        .bytes()
+        .map(|x| if x == b'#' { 0xe9 } else { x })
+        .collect::<Vec<_>>();
+
+    let output = smtp_session(mail_config(), lark, bytes).await;
+    assert!(output.contains("250 2.0.0 OK"), "{}", output);
+
+    let html = mock.sent()[0].body["body_html"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(html.contains("<p>café</p>"), "{}", html);
+    assert!(!html.contains(".."), "{}", html);
+}
+
+#[tokio::test]
+async fn null_sender_sends_from_token_owner() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("null-sender", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+
+    let script = send_script("alice@example.com", "bob@example.com", "Bounce")
+        .replace("MAIL FROM:<alice@example.com>", "MAIL FROM:<>");
+    let output = smtp_session(mail_config(), lark, &script).await;
+    assert!(
+        output.contains("250 2.0.0 OK queued as msg-1"),
+        "{}",
+        output
+    );
+
+    let sent = mock.sent();
+    assert_eq!(sent[0].mailbox, "me");
+    assert!(sent[0].access_token.starts_with("user-alice-"));
+}
+
+#[tokio::test]
+async fn sends_with_token_of_oauth_session() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("oauth", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    let mut config = (*mail_config()).clone();
+    config.sasl = Registry::builtin(&["XOAUTH2".to_string(), "OAUTHBEARER".to_string()]).unwrap();
+    config.tokens = Some(lark.clone());
+    let config = Arc::new(config);
+
+    let token = mock.issue_access_token("carol");
+    let xoauth2 = BASE64_STANDARD.encode(format!(
+        "user=carol@example.com\x01auth=Bearer {}\x01\x01",
+        token
+    ));
+    let script = send_script("carol@example.com", "bob@example.com", "OAuth").replace(
+        &format!("AUTH PLAIN {}", plain_encode("relay", "secret")),
+        &format!(
+            "AUTH XOAUTH2 {}\r\nMAIL FROM:<alice@example.com>\r\nRSET",
+            xoauth2
+        ),
+    );
+    let output = smtp_session(config.clone(), lark.clone(), &script).await;
+    assert!(
+        output.contains("250-AUTH XOAUTH2 OAUTHBEARER\r\n"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("235 2.7.0 Authentication successful\r\n550 5.7.1 "),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("250 2.0.0 OK queued as msg-1"),
+        "{}",
+        output
+    );
+    let sent = mock.sent();
+    assert_eq!(sent[0].mailbox, "carol@example.com");
+    assert_eq!(sent[0].access_token, token);
+
+    let bearer = BASE64_STANDARD.encode("n,a=carol@example.com,\x01auth=Bearer bogus\x01\x01");
+    let output = smtp_session(
+        config,
+        lark,
+        format!(
+            "EHLO client.test\r\nAUTH OAUTHBEARER {}\r\nAQ==\r\n",
+            bearer
+        ),
+    )
+    .await;
+    let error = BASE64_STANDARD
+        .encode(r#"{"status":"invalid_token","scope":"mail:user_mailbox.message:send"}"#);
+    assert!(
+        output.ends_with(&format!(
+            "334 {}\r\n535 5.7.8 Authentication credentials invalid\r\n",
+            error
+        )),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn spooled_session_token_fails_permanently_once_rejected() {
+    let mock = MockLark::start().await;
+    let dir = mock.data_dir("oauth-spool", json!({ "code": "alice" }));
+    let lark = Arc::new(LarkMail::from_data_dir(&dir).await.unwrap());
+    let spool = Spool::new(format!("{}/spool", dir), "smtp.test", lark.clone()).unwrap();
+    let mut config = (*mail_config()).clone();
+    config.sasl = Registry::builtin(&["XOAUTH2".to_string()]).unwrap();
+    config.tokens = Some(lark);
+
+    let token = mock.issue_access_token("carol");
+    let xoauth2 = BASE64_STANDARD.encode(format!(
+        "user=carol@example.com\x01auth=Bearer {}\x01\x01",
+        token
+    ));
+    let script = send_script("carol@example.com", "bob@example.com", "Late").replace(
+        &format!("AUTH PLAIN {}", plain_encode("relay", "secret")),
+        &format!("AUTH XOAUTH2 {}", xoauth2),
+    );
+    mock.inject_send_error(400, 99991677, "user access token expired");
+    let output = smtp_session(Arc::new(config), spool, &script).await;
+    assert!(output.contains("250 2.0.0 OK queued as "), "{}", output);
+
+    let dead = std::path::Path::new(&dir).join("spool/dead");
+    let mut entry = None;
+    for _ in 0..100 {
+        entry = std::fs::read_dir(&dead)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .find(|x| x.extension().is_some_and(|x| x == "json"));
+        if entry.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let entry = entry.expect("message was not moved to dead letters");
+    let contents = std::fs::read_to_string(&entry).unwrap();
+    assert!(!contents.contains(&token), "{}", contents);
+    assert!(contents.contains("554 5.7.0 "), "{}", contents);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&entry).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let mut sent = Vec::new();
+    for _ in 0..100 {
+        sent = mock.sent();
+        if !sent.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(sent.len(), 1, "bounce was not sent");
+    assert_eq!(
+        sent[0].body["subject"],
+        "Delivery Status Notification (Failure)"
+    );
+    assert_eq!(sent[0].body["to"][0]["mail_address"], "carol@example.com");
+    assert_ne!(sent[0].access_token, token);
+}
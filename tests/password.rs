@@ -0,0 +1,47 @@
+use smtp2larkapi::password::{dummy_hash, hash_password, plaintext, verify_password};
+
+fn check(stored: &str) {
+    assert!(verify_password(stored, "secret"), "{}", stored);
+    assert!(!verify_password(stored, "Secret"), "{}", stored);
+    assert!(!verify_password(stored, ""), "{}", stored);
+}
+
+#[test]
+fn verifies_every_stored_format() {
+    let argon2 = hash_password("secret").unwrap();
+    assert!(argon2.starts_with("$argon2id$"));
+    check(&argon2);
+
+    let bcrypt = bcrypt::hash("secret", 4).unwrap();
+    assert!(bcrypt.starts_with("$2b$"));
+    check(&bcrypt);
+
+    let params = sha_crypt::Sha512Params::new(1000).unwrap();
+    let sha512 = sha_crypt::sha512_simple("secret", &params).unwrap();
+    assert!(sha512.starts_with("$6$"));
+    check(&sha512);
+
+    check("secret");
+    assert!(!verify_password("secret", "secret "));
+}
+
+#[test]
+fn hashes_are_not_usable_as_plaintext() {
+    let argon2 = hash_password("secret").unwrap();
+    assert!(!verify_password(&argon2, &argon2));
+    assert_eq!(plaintext(&argon2), None);
+    assert_eq!(plaintext("$2b$04$invalid"), None);
+    assert_eq!(plaintext("$6$invalid"), None);
+    assert_eq!(plaintext("secret"), Some("secret"));
+    // A malformed hash is rejected rather than compared as plaintext.
+    assert!(!verify_password("$2b$04$invalid", "$2b$04$invalid"));
+    assert!(!verify_password("$6$invalid", "$6$invalid"));
+}
+
+#[test]
+fn empty_stored_value_never_matches() {
+    assert!(!verify_password("", ""));
+    assert!(!verify_password("", "secret"));
+    assert_eq!(plaintext(""), None);
+    assert!(!verify_password(dummy_hash(), ""));
+}
@@ -0,0 +1,233 @@
+mod common;
+
+use base64::prelude::*;
+use common::{mail_config, smtp_session, MemorySink};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use smtp2larkapi::password::hash_password;
+use smtp2larkapi::sasl::Registry;
+use smtp2larkapi::smtp_server::{plain_encode, serve, Account, MailConfig};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+fn config_with(mechanisms: &[&str]) -> Arc<MailConfig> {
+    let mut config = (*mail_config()).clone();
+    let names = mechanisms.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    config.sasl = Registry::builtin(&names).unwrap();
+    config.accounts.push(Account {
+        user: "hashed".to_string(),
+        passwd: hash_password("secret").unwrap(),
+        ..Default::default()
+    });
+    Arc::new(config)
+}
+
+async fn read_reply(client: &mut DuplexStream) -> String {
+    let mut buf = vec![0; 4096];
+    let n = tokio::time::timeout(Duration::from_secs(10), client.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    String::from_utf8_lossy(&buf[..n]).to_string()
+}
+
+/// Starts a session, sends EHLO and `AUTH <mechanism>`, and returns the decoded first challenge.
+async fn start_auth(config: Arc<MailConfig>, mechanism: &str) -> (DuplexStream, Vec<u8>) {
+    let (mut client, server) = tokio::io::duplex(1 << 16);
+    tokio::spawn(serve(server, config, Arc::new(MemorySink::default())));
+    read_reply(&mut client).await;
+    client.write_all(b"EHLO client.test\r\n").await.unwrap();
+    read_reply(&mut client).await;
+    client
+        .write_all(format!("AUTH {}\r\n", mechanism).as_bytes())
+        .await
+        .unwrap();
+    let challenge = challenge(&read_reply(&mut client).await);
+    (client, challenge)
+}
+
+fn challenge(reply: &str) -> Vec<u8> {
+    let encoded = reply
+        .strip_prefix("334 ")
+        .unwrap_or_else(|| panic!("{}", reply));
+    BASE64_STANDARD.decode(encoded.trim_end()).unwrap()
+}
+
+async fn respond(client: &mut DuplexStream, response: &[u8]) -> String {
+    let line = format!("{}\r\n", BASE64_STANDARD.encode(response));
+    client.write_all(line.as_bytes()).await.unwrap();
+    read_reply(client).await
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[tokio::test]
+async fn advertises_only_enabled_mechanisms() {
+    let sink = Arc::new(MemorySink::default());
+    let output = smtp_session(
+        mail_config(),
+        sink.clone(),
+        "EHLO client.test\r\nAUTH CRAM-MD5\r\n",
+    )
+    .await;
+    assert!(output.contains("250-AUTH LOGIN PLAIN\r\n"), "{}", output);
+    assert!(output.contains("250-AUTH=LOGIN\r\n"), "{}", output);
+    assert!(
+        output.ends_with("504 5.5.4 Unrecognized authentication type\r\n"),
+        "{}",
+        output
+    );
+
+    let output = smtp_session(
+        config_with(&["scram-sha-256", "CRAM-MD5"]),
+        sink,
+        "EHLO client.test\r\nAUTH PLAIN\r\n",
+    )
+    .await;
+    assert!(
+        output.contains("250-AUTH SCRAM-SHA-256 CRAM-MD5\r\n"),
+        "{}",
+        output
+    );
+    assert!(!output.contains("AUTH=LOGIN"), "{}", output);
+    assert!(output.ends_with("504 5.5.4 Unrecognized authentication type\r\n"));
+
+    assert!(Registry::builtin(&["DIGEST-MD5".to_string()]).is_err());
+}
+
+#[tokio::test]
+async fn disconnects_only_after_repeated_failures() {
+    let wrong = format!("AUTH PLAIN {}\r\n", plain_encode("relay", "wrong"));
+    let output = smtp_session(
+        mail_config(),
+        Arc::new(MemorySink::default()),
+        format!(
+            "EHLO client.test\r\nAUTH FOO\r\nNOOP\r\n{}{}NOOP\r\n{}NOOP\r\n",
+            wrong, wrong, wrong
+        ),
+    )
+    .await;
+    let replies = output
+        .lines()
+        .skip_while(|x| !x.starts_with("504"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        replies,
+        [
+            "504 5.5.4 Unrecognized authentication type",
+            "250 2.0.0 OK",
+            "535 5.7.8 Authentication credentials invalid",
+            "535 5.7.8 Authentication credentials invalid",
+            "250 2.0.0 OK",
+            "535 5.7.8 Authentication credentials invalid",
+        ],
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn cram_md5_needs_a_plaintext_password() {
+    let config = config_with(&["CRAM-MD5"]);
+    for (user, accepted) in [("relay", true), ("hashed", false)] {
+        let (mut client, challenge) = start_auth(config.clone(), "CRAM-MD5").await;
+        let text = String::from_utf8(challenge.clone()).unwrap();
+        assert!(
+            text.starts_with('<') && text.ends_with("@smtp.test>"),
+            "{}",
+            text
+        );
+
+        let mut mac = Hmac::<md5::Md5>::new_from_slice(b"secret").unwrap();
+        mac.update(&challenge);
+        let digest = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        let reply = respond(&mut client, format!("{} {}", user, digest).as_bytes()).await;
+        match accepted {
+            true => assert_eq!(reply, "235 2.7.0 Authentication successful\r\n"),
+            false => assert_eq!(reply, "535 5.7.8 Authentication credentials invalid\r\n"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn scram_sha_256_proves_both_sides() {
+    let config = config_with(&["SCRAM-SHA-256"]);
+    for (password, accepted) in [("secret", true), ("wrong", false)] {
+        let (mut client, challenge) = start_auth(config.clone(), "SCRAM-SHA-256").await;
+        assert!(challenge.is_empty());
+
+        let client_first_bare = "n=relay,r=clientnonce";
+        let reply = respond(&mut client, format!("n,,{}", client_first_bare).as_bytes()).await;
+        let server_first = String::from_utf8(self::challenge(&reply)).unwrap();
+        let mut attributes = server_first.split(',');
+        let nonce = attributes.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = attributes.next().unwrap().strip_prefix("s=").unwrap();
+        let iterations = attributes.next().unwrap().strip_prefix("i=").unwrap();
+        assert!(nonce.starts_with("clientnonce") && nonce.len() > "clientnonce".len());
+
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            password.as_bytes(),
+            &BASE64_STANDARD.decode(salt).unwrap(),
+            iterations.parse().unwrap(),
+            &mut salted_password,
+        );
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let signature = hmac_sha256(&Sha256::digest(&client_key), auth_message.as_bytes());
+        let proof = client_key
+            .iter()
+            .zip(signature)
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+        let client_final = format!("{},p={}", without_proof, BASE64_STANDARD.encode(proof));
+        let reply = respond(&mut client, client_final.as_bytes()).await;
+
+        if !accepted {
+            assert_eq!(reply, "535 5.7.8 Authentication credentials invalid\r\n");
+            continue;
+        }
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+        let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+        assert_eq!(
+            String::from_utf8(self::challenge(&reply)).unwrap(),
+            format!("v={}", BASE64_STANDARD.encode(server_signature))
+        );
+        assert_eq!(
+            respond(&mut client, b"").await,
+            "235 2.7.0 Authentication successful\r\n"
+        );
+    }
+}
+
+#[tokio::test]
+async fn auth_exchange_can_be_cancelled() {
+    let sink = Arc::new(MemorySink::default());
+    let output = smtp_session(
+        mail_config(),
+        sink,
+        "EHLO client.test\r\nAUTH LOGIN\r\n*\r\nAUTH PLAIN not-base64!\r\nNOOP\r\n",
+    )
+    .await;
+    assert!(
+        output.contains("334 VXNlcm5hbWU6\r\n501 5.0.0 Authentication cancelled\r\n"),
+        "{}",
+        output
+    );
+    assert!(
+        output.ends_with("501 5.5.2 Cannot decode response\r\n250 2.0.0 OK\r\n"),
+        "{}",
+        output
+    );
+}
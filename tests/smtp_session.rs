@@ -0,0 +1,679 @@
+mod common;
+
+use common::{mail_config, send_script, smtp_session, MemorySink};
+use smtp2larkapi::sasl::Registry;
+use smtp2larkapi::smtp_server::{listen, plain_encode, serve, Account, MailConfig};
+use smtp2larkapi::socket::{self, Socket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn login() -> String {
+    format!(
+        "EHLO client.test\r\nAUTH PLAIN {}\r\n",
+        plain_encode("relay", "secret")
+    )
+}
+
+/// Returns whatever the server has written in one read, as pipelined replies arrive together.
+async fn read_replies(client: &mut tokio::io::DuplexStream) -> String {
+    let mut buf = vec![0; 4096];
+    let n = tokio::time::timeout(Duration::from_secs(10), client.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    String::from_utf8_lossy(&buf[..n]).to_string()
+}
+
+#[tokio::test]
+async fn delivers_each_transaction_of_a_session() {
+    let sink = Arc::new(MemorySink::default());
+    let script = login()
+        + "MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n\
+           Subject: first\r\n\r\none\r\n.\r\n\
+           MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<carol@example.com>\r\n\
+           RSET\r\n\
+           MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<dave@example.com>\r\n\
+           DATA\r\n\
+           Subject: second\r\n\r\ntwo\r\n.\r\n\
+           QUIT\r\n";
+    let output = smtp_session(mail_config(), sink.clone(), &script).await;
+    assert!(output.ends_with("221 2.0.0 Bye\r\n"), "{}", output);
+
+    let received = sink.take();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].to.len(), 1);
+    assert_eq!(received[0].to[0].mail_address, "bob@example.com");
+    assert!(String::from_utf8_lossy(&received[0].body).contains("one"));
+    assert_eq!(received[1].to.len(), 1);
+    assert_eq!(received[1].to[0].mail_address, "dave@example.com");
+    let second = String::from_utf8_lossy(&received[1].body);
+    assert!(second.contains("two"));
+    assert!(!second.contains("one"));
+}
+
+#[tokio::test]
+async fn unstuffs_leading_dots_and_keeps_8bit_bytes() {
+    let sink = Arc::new(MemorySink::default());
+    let mut script = (login()
+        + "MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n\
+           Subject: dots\r\n\r\n\
+           ..\r\n\
+           ...twice\r\n\
+           .hidden\r\n\
+           caf")
+        .into_bytes();
+    script.extend_from_slice(b"\xe9 \xff\r\n.\r\nQUIT\r\n");
+    let output = smtp_session(mail_config(), sink.clone(), script).await;
+    assert!(
+        output.ends_with("250 2.0.0 OK\r\n221 2.0.0 Bye\r\n"),
+        "{}",
+        output
+    );
+
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    assert_eq!(
+        received[0].body,
+        b"Subject: dots\r\n\r\n.\r\n..twice\r\nhidden\r\ncaf\xe9 \xff\r\n"
+    );
+}
+
+#[tokio::test]
+async fn rejects_bdat_chunk_larger_than_the_size_limit() {
+    let sink = Arc::new(MemorySink::default());
+    let script = login()
+        + "MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           BDAT 1\r\n\
+           x"
+        + &format!("BDAT {} LAST\r\n", usize::MAX);
+    let output = smtp_session(mail_config(), sink.clone(), script).await;
+    assert!(
+        output.ends_with(
+            "250 2.0.0 1 octets received\r\n\
+             552 5.3.4 Message size exceeds fixed maximum message size\r\n"
+        ),
+        "{}",
+        output
+    );
+    assert!(sink.take().is_empty());
+}
+
+#[tokio::test]
+async fn dsn_is_offered_only_by_sinks_that_report_status() {
+    let sink = Arc::new(MemorySink::default());
+    let script = login()
+        + "MAIL FROM:<alice@example.com> RET=HDRS\r\n\
+           MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com> NOTIFY=DELAY\r\n";
+    let output = smtp_session(mail_config(), sink, script).await;
+    assert!(!output.contains("250-DSN"), "{}", output);
+    assert!(
+        output.ends_with(
+            "555 5.5.4 Unsupported parameter RET\r\n\
+             250 2.1.0 OK\r\n\
+             555 5.5.4 Unsupported parameter NOTIFY\r\n"
+        ),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn bare_lf_is_normalized_and_does_not_end_data() {
+    let sink = Arc::new(MemorySink::default());
+    let script = login()
+        + "MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n\
+           hi\n.\nMAIL FROM:<a@b.c>\r\n\
+           RCPT TO:<z@e.f>\r\n\
+           DATA\r\n\
+           smuggled\r\n\
+           lf only\n.\r\n\
+           .\r\n\
+           NOOP\r\n";
+    let output = smtp_session(mail_config(), sink.clone(), script).await;
+    assert!(
+        output.ends_with(
+            "354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+             250 2.0.0 OK\r\n\
+             250 2.0.0 OK\r\n"
+        ),
+        "{}",
+        output
+    );
+    // One message, with its bare LFs turned into CRLF and the would-be commands as text.
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    assert_eq!(
+        received[0].body,
+        b"hi\r\n\r\nMAIL FROM:<a@b.c>\r\nRCPT TO:<z@e.f>\r\nDATA\r\nsmuggled\r\nlf only\r\n\r\n"
+    );
+}
+
+#[tokio::test]
+async fn accepts_binary_message_in_bdat_chunks() {
+    let sink = Arc::new(MemorySink::default());
+    let mut script = (login()
+        + "MAIL FROM:<alice@example.com> BODY=BINARYMIME\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n\
+           BDAT 22\r\n\
+           Subject: chunks\r\n\r\n.\r\n\
+           BDAT 5 LAST\r\n")
+        .into_bytes();
+    script.extend_from_slice(b"\x00\xff\r\n.");
+    script.extend_from_slice(
+        b"BDAT 3 LAST\r\nabcMAIL FROM:<alice@example.com>\r\nBDAT 2\r\nxyQUIT\r\n",
+    );
+    let output = smtp_session(mail_config(), sink.clone(), script).await;
+    let replies = output.lines().collect::<Vec<_>>();
+    assert!(replies.contains(&"250-CHUNKING"), "{}", output);
+    assert!(replies.contains(&"250 BINARYMIME"), "{}", output);
+    assert!(
+        replies.contains(&"503 5.6.1 BINARYMIME requires BDAT"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"250 2.0.0 22 octets received"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"503 5.5.1 Need MAIL command"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"503 5.5.1 Need RCPT command"),
+        "{}",
+        output
+    );
+    assert_eq!(replies.last(), Some(&"221 2.0.0 Bye"));
+
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    assert_eq!(
+        received[0].body,
+        b"Subject: chunks\r\n\r\n.\r\n\x00\xff\r\n."
+    );
+}
+
+#[tokio::test]
+async fn enforces_size_limit() {
+    let sink = Arc::new(MemorySink::default());
+    let mut config = (*mail_config()).clone();
+    config.max_size = 64;
+    let config = Arc::new(config);
+    let body = "x".repeat(100);
+    let script = login()
+        + "MAIL FROM:<alice@example.com> SIZE=65\r\n\
+           MAIL FROM:<alice@example.com> SIZE=64\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n"
+        + &body
+        + "\r\n.\r\n\
+           MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n\
+           small\r\n.\r\n\
+           QUIT\r\n";
+    let output = smtp_session(config, sink.clone(), &script).await;
+    let replies = output.lines().collect::<Vec<_>>();
+    assert!(replies.contains(&"250-SIZE 64"), "{}", output);
+    assert_eq!(
+        replies
+            .iter()
+            .filter(|x| x.starts_with("552 5.3.4 "))
+            .count(),
+        2,
+        "{}",
+        output
+    );
+    assert_eq!(replies.last(), Some(&"221 2.0.0 Bye"));
+
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, b"small\r\n");
+}
+
+#[tokio::test]
+async fn caps_command_lines_but_not_data_lines() {
+    let sink = Arc::new(MemorySink::default());
+    let long = "x".repeat(20000);
+    let script = format!("NOOP {long}\r\nNOOP\r\n")
+        + &login()
+        + "MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           DATA\r\n"
+        + &long
+        + "\r\n.\r\n\
+           QUIT\r\n";
+    let output = smtp_session(mail_config(), sink.clone(), &script).await;
+    assert!(
+        output.contains("\r\n500 5.5.2 Line too long\r\n250 2.0.0 OK\r\n"),
+        "{}",
+        output
+    );
+    assert!(output.ends_with("221 2.0.0 Bye\r\n"), "{}", output);
+
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, format!("{long}\r\n").as_bytes());
+}
+
+#[test]
+fn matches_allowed_senders() {
+    let unrestricted = Account::default();
+    assert!(unrestricted.may_send_as("anyone@anywhere.test"));
+
+    let account = Account {
+        senders: Some(vec![
+            "Alice@Example.com".to_string(),
+            "*@Team.example.com".to_string(),
+        ]),
+        ..Default::default()
+    };
+    for address in [
+        "alice@example.com",
+        "ALICE@EXAMPLE.COM",
+        "bob@team.example.com",
+        "Bob@TEAM.Example.com",
+    ] {
+        assert!(account.may_send_as(address), "{}", address);
+    }
+    for address in [
+        "mallory@example.com",
+        "alice@example.org",
+        "bob@sub.team.example.com",
+        "bob@evilteam.example.com",
+        "team.example.com",
+        "",
+    ] {
+        assert!(!account.may_send_as(address), "{}", address);
+    }
+
+    let nobody = Account {
+        senders: Some(Vec::new()),
+        ..Default::default()
+    };
+    assert!(!nobody.may_send_as("alice@example.com"));
+}
+
+#[tokio::test]
+async fn rejects_senders_the_account_does_not_own() {
+    let sink = Arc::new(MemorySink::default());
+    let mut config = (*mail_config()).clone();
+    config.accounts[0].senders = Some(vec!["*@team.example.com".to_string()]);
+    let script = login()
+        + "MAIL FROM:<mallory@example.com>\r\n\
+           MAIL FROM:<Bob@Team.Example.com>\r\n";
+    let output = smtp_session(Arc::new(config), sink, script).await;
+    assert!(
+        output.ends_with(
+            "550 5.7.1 Sender address rejected: not owned by user relay\r\n\
+             250 2.1.0 OK\r\n"
+        ),
+        "{}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn answers_pipelined_group_in_order() {
+    let sink = Arc::new(MemorySink::default());
+    let script = login()
+        + "MAIL FROM:<alice@example.com>\r\n\
+           RCPT TO:<bob@example.com>\r\n\
+           RCPT TO:<>\r\n\
+           RCPT TO:<carol@example.com>\r\n\
+           DATA\r\n\
+           Subject: group\r\n\r\nbody\r\n.\r\n\
+           QUIT\r\n";
+    let output = smtp_session(mail_config(), sink.clone(), &script).await;
+    let replies = output.lines().collect::<Vec<_>>();
+    let group = &replies[replies.len() - 7..];
+    assert_eq!(
+        group,
+        [
+            "250 2.1.0 OK",
+            "250 2.1.5 OK",
+            "501 5.1.3 Null forward-path is not allowed",
+            "250 2.1.5 OK",
+            "354 Start mail input; end with <CRLF>.<CRLF>",
+            "250 2.0.0 OK",
+            "221 2.0.0 Bye",
+        ],
+        "{}",
+        output
+    );
+
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    let to = received[0]
+        .to
+        .iter()
+        .map(|x| x.mail_address.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(to, ["bob@example.com", "carol@example.com"]);
+}
+
+#[tokio::test]
+async fn batches_replies_to_pipelined_commands() {
+    let sink = Arc::new(MemorySink::default());
+    let (mut client, server) = tokio::io::duplex(1 << 20);
+    let session = tokio::spawn(serve(server, mail_config(), sink.clone()));
+
+    assert!(read_replies(&mut client).await.starts_with("220 "));
+    client.write_all(login().as_bytes()).await.unwrap();
+    let replies = read_replies(&mut client).await;
+    assert!(replies.contains("250-PIPELINING\r\n"), "{}", replies);
+    assert!(
+        replies.contains("250-ENHANCEDSTATUSCODES\r\n"),
+        "{}",
+        replies
+    );
+    assert!(
+        replies.ends_with("235 2.7.0 Authentication successful\r\n"),
+        "{}",
+        replies
+    );
+
+    client
+        .write_all(
+            b"MAIL FROM:<alice@example.com>\r\n\
+              RCPT TO:<bob@example.com>\r\n\
+              RCPT TO:bob\r\n\
+              DATA\r\n",
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        read_replies(&mut client).await,
+        "250 2.1.0 OK\r\n250 2.1.5 OK\r\n501 5.1.3 Address must be enclosed in <>\r\n\
+         354 Start mail input; end with <CRLF>.<CRLF>\r\n"
+    );
+
+    client
+        .write_all(b"Subject: batch\r\n\r\nbody\r\n.\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        read_replies(&mut client).await,
+        "250 2.0.0 OK\r\n221 2.0.0 Bye\r\n"
+    );
+    session.await.unwrap().unwrap();
+    assert_eq!(sink.take().len(), 1);
+}
+
+#[tokio::test]
+async fn accepts_null_sender_and_records_parameters() {
+    let sink = Arc::new(MemorySink::default());
+    let script = login()
+        + "MAIL FROM:<> BODY=8BITMIME SIZE=100\r\n\
+           RCPT TO:<\"bob smith\"@example.com>\r\n\
+           RCPT TO:<j\u{f6}rg@b\u{fc}cher.example>\r\n\
+           RCPT TO:<@relay.example:carol@example.com>\r\n\
+           RCPT TO:<dave@example.com> FOO=bar\r\n\
+           DATA\r\n\
+           Subject: bounce\r\n\r\nbody\r\n.\r\n\
+           MAIL FROM:<alice@example.com> SMTPUTF8\r\n\
+           RCPT TO:<j\u{f6}rg@b\u{fc}cher.example>\r\n\
+           DATA\r\n\
+           Subject: utf8\r\n\r\nbody\r\n.\r\n\
+           QUIT\r\n";
+    let output = smtp_session(mail_config(), sink.clone(), &script).await;
+    let replies = output.lines().collect::<Vec<_>>();
+    assert!(
+        replies.contains(&"553 5.6.7 Non-ASCII address requires SMTPUTF8"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"555 5.5.4 Unsupported parameter FOO"),
+        "{}",
+        output
+    );
+    assert_eq!(replies.last(), Some(&"221 2.0.0 Bye"));
+
+    let received = sink.take();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].from.mail_address, "");
+    assert_eq!(received[0].mail_params.get("body"), Some("8BITMIME"));
+    assert_eq!(received[0].mail_params.get("SIZE"), Some("100"));
+    let to = received[0]
+        .to
+        .iter()
+        .map(|x| x.mail_address.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(to, ["\"bob smith\"@example.com", "carol@example.com"]);
+    assert_eq!(received[0].rcpt_params.len(), 2);
+
+    assert!(received[1].mail_params.contains("SMTPUTF8"));
+    assert_eq!(
+        received[1].to[0].mail_address,
+        "j\u{f6}rg@b\u{fc}cher.example"
+    );
+}
+
+#[tokio::test]
+async fn rejects_commands_out_of_sequence() {
+    let sink = Arc::new(MemorySink::default());
+    let output = smtp_session(
+        mail_config(),
+        sink.clone(),
+        &(login() + "RCPT TO:<bob@example.com>\r\n"),
+    )
+    .await;
+    assert!(
+        output.ends_with("503 5.5.1 Need MAIL command\r\n"),
+        "{}",
+        output
+    );
+
+    let output = smtp_session(
+        mail_config(),
+        sink.clone(),
+        &(login() + "MAIL FROM:<alice@example.com>\r\nDATA\r\n"),
+    )
+    .await;
+    assert!(
+        output.ends_with("503 5.5.1 Need RCPT command\r\n"),
+        "{}",
+        output
+    );
+    assert!(sink.take().is_empty());
+}
+
+#[tokio::test]
+async fn answers_basic_verbs_and_survives_unknown_commands() {
+    let sink = Arc::new(MemorySink::default());
+    let script = "EHLO client.test\r\n\
+                  NOOP\r\n\
+                  VRFY bob\r\n\
+                  HELP\r\n\
+                  FOO bar\r\n\
+                  EXPN staff\r\n\
+                  MAIL FROM:<alice@example.com>\r\n\
+                  QUIT\r\n";
+    let output = smtp_session(mail_config(), sink, script).await;
+    let replies = output.lines().collect::<Vec<_>>();
+    assert!(replies.contains(&"250 2.0.0 OK"), "{}", output);
+    assert!(
+        replies.iter().any(|x| x.starts_with("252 2.5.0 ")),
+        "{}",
+        output
+    );
+    assert!(
+        replies.iter().any(|x| x.starts_with("214 2.0.0 ")),
+        "{}",
+        output
+    );
+    assert!(replies.contains(&"500 5.5.2 Unknown command"), "{}", output);
+    assert!(
+        replies.contains(&"502 5.5.1 Command not implemented"),
+        "{}",
+        output
+    );
+    assert!(
+        replies.contains(&"530 5.7.0 Authentication required"),
+        "{}",
+        output
+    );
+    assert_eq!(replies.last(), Some(&"221 2.0.0 Bye"));
+}
+
+#[tokio::test]
+async fn every_reply_is_well_formed() {
+    let sink = Arc::new(MemorySink::default());
+    let script = "EHLO client.test\r\n\
+                  MAIL FROM:<alice@example.com>\r\n\
+                  AUTH LOGIN\r\n\
+                  cmVsYXk=\r\n\
+                  c2VjcmV0\r\n\
+                  MAIL FROM:alice\r\n\
+                  MAIL FROM:<alice@example.com> SIZE=x\r\n\
+                  RCPT TO:<bob@example.com>\r\n\
+                  BDAT 1 NOW\r\n\
+                  DATA\r\n\
+                  body\r\n.\r\n\
+                  HELP\r\n\
+                  RSET\r\n\
+                  BOGUS\r\n\
+                  QUIT\r\n";
+    let output = smtp_session(mail_config(), sink, script).await;
+    assert!(output.ends_with("\r\n"), "{}", output);
+    for line in output.split_terminator("\r\n") {
+        let (code, rest) = line.split_at(3);
+        assert!(code.parse::<u16>().is_ok(), "{}", line);
+        assert!(rest.starts_with(' ') || rest.starts_with('-'), "{}", line);
+        // The greeting, the EHLO list and intermediate replies carry no enhanced code.
+        if code == "334" || code == "354" || line.starts_with("220 smtp.test") {
+            continue;
+        }
+        if code == "250" && !rest[1..].starts_with("2.") {
+            continue;
+        }
+        let class = &rest[1..2];
+        assert_eq!(class, &code[..1], "{}", line);
+        assert_eq!(&rest[2..3], ".", "{}", line);
+    }
+}
+
+/// Writes `script` and reads until the server closes the connection.
+async fn converse<S: AsyncReadExt + AsyncWriteExt + Unpin>(mut client: S, script: &str) -> String {
+    client.write_all(script.as_bytes()).await.unwrap();
+    let mut output = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), client.read_to_end(&mut output))
+        .await
+        .unwrap()
+        .unwrap();
+    String::from_utf8_lossy(&output).to_string()
+}
+
+#[tokio::test]
+async fn listeners_with_own_policies_share_one_sink() {
+    let sink = Arc::new(MemorySink::default());
+    let mut restricted = (*mail_config()).clone();
+    restricted.sasl = Registry::builtin(&["CRAM-MD5".to_string()]).unwrap();
+    let mut addresses = Vec::new();
+    for config in [mail_config(), Arc::new(restricted)] {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        addresses.push(listener.local_addr().unwrap());
+        tokio::spawn(listen(Socket::Tcp(listener), config, sink.clone()));
+    }
+
+    let mut outputs = Vec::new();
+    for (address, subject) in addresses.iter().zip(["first", "second"]) {
+        let client = tokio::net::TcpStream::connect(address).await.unwrap();
+        let script = send_script("alice@example.com", "bob@example.com", subject);
+        outputs.push(converse(client, &script).await);
+    }
+
+    assert!(
+        outputs[0].contains("250-AUTH LOGIN PLAIN\r\n"),
+        "{}",
+        outputs[0]
+    );
+    assert!(outputs[0].ends_with("221 2.0.0 Bye\r\n"), "{}", outputs[0]);
+    assert!(
+        outputs[1].contains("250-AUTH CRAM-MD5\r\n"),
+        "{}",
+        outputs[1]
+    );
+    assert!(
+        outputs[1].contains("504 5.5.4 Unrecognized authentication type\r\n"),
+        "{}",
+        outputs[1]
+    );
+    let received = sink.take();
+    assert_eq!(received.len(), 1);
+    assert!(String::from_utf8_lossy(&received[0].body).contains("Subject: first\r\n"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_peers_log_in_by_uid() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let dir = std::env::temp_dir().join(format!("smtp2larkapi-unix-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("smtp.sock");
+    let path = path.to_str().unwrap();
+    std::fs::write(path, b"").unwrap();
+    assert!(socket::bind_unix(path, None, None, None).is_err());
+    std::fs::remove_file(path).unwrap();
+
+    // A stale socket file from an earlier run is replaced.
+    drop(socket::bind_unix(path, None, None, None).unwrap());
+    let listener = socket::bind_unix(path, Some("600"), None, None).unwrap();
+    let metadata = std::fs::metadata(path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    // The private directory it was staged in is gone.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    let script = "EHLO client.test\r\n\
+                  MAIL FROM:<alice@example.com>\r\n\
+                  RCPT TO:<bob@example.com>\r\n\
+                  DATA\r\n\
+                  Subject: local\r\n\
+                  \r\n\
+                  hello\r\n\
+                  .\r\n\
+                  QUIT\r\n";
+    let sink = Arc::new(MemorySink::default());
+    let mut config: MailConfig = (*mail_config()).clone();
+    config.accounts[0].peer_uids = vec![metadata.uid()];
+    tokio::spawn(listen(
+        Socket::Unix(listener),
+        Arc::new(config),
+        sink.clone(),
+    ));
+    let client = tokio::net::UnixStream::connect(path).await.unwrap();
+    let output = converse(client, script).await;
+    assert!(output.contains("354 "), "{}", output);
+    assert!(output.ends_with("221 2.0.0 Bye\r\n"), "{}", output);
+    assert_eq!(sink.take().len(), 1);
+
+    // Without a mapped uid the peer still has to authenticate.
+    let listener = socket::bind_unix(path, None, None, None).unwrap();
+    tokio::spawn(listen(Socket::Unix(listener), mail_config(), sink.clone()));
+    let client = tokio::net::UnixStream::connect(path).await.unwrap();
+    let output = converse(client, script).await;
+    assert!(
+        output.contains("530 5.7.0 Authentication required\r\n"),
+        "{}",
+        output
+    );
+    assert!(sink.take().is_empty());
+}
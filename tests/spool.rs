@@ -0,0 +1,99 @@
+mod common;
+
+use async_trait::async_trait;
+use common::{mail_config, send_script, smtp_session};
+use smtp2larkapi::mail_sink::{Delivery, DeliveryError, MailSink};
+use smtp2larkapi::smtp_server::MailData;
+use smtp2larkapi::spool::{Retry, Spool};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sink that fails every delivery transiently and records when each attempt was made.
+#[derive(Default)]
+struct FlakySink {
+    attempts: Mutex<Vec<(Instant, Vec<u8>)>>,
+}
+
+#[async_trait]
+impl MailSink for FlakySink {
+    async fn deliver(&self, mail_data: MailData) -> Result<Delivery, anyhow::Error> {
+        self.attempts
+            .lock()
+            .unwrap()
+            .push((Instant::now(), mail_data.body));
+        Err(DeliveryError::new(451, "4.3.0", "try again later").into())
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_the_maximum() {
+    let retry = Retry::default();
+    let delays = (1..=10).map(|x| retry.delay(x)).collect::<Vec<_>>();
+    assert_eq!(
+        delays,
+        [60, 120, 240, 480, 960, 1920, 3840, 7680, 15360, 21600]
+    );
+    assert_eq!(retry.delay(u32::MAX), 21600);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let dir = std::env::temp_dir().join(format!("smtp2larkapi-spool-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let sink = Arc::new(FlakySink::default());
+    let retry = Retry {
+        base_secs: 1,
+        max_secs: 1,
+        max_attempts: 3,
+        ..Default::default()
+    };
+    let spool = Spool::with_retry(dir.join("spool"), "smtp.test", sink.clone(), retry).unwrap();
+
+    let output = smtp_session(
+        mail_config(),
+        spool,
+        send_script("alice@example.com", "bob@example.com", "Retry me"),
+    )
+    .await;
+    assert!(output.contains("250 2.0.0 OK queued as "), "{}", output);
+
+    let dead = dir.join("spool/dead");
+    let mut entry = None;
+    for _ in 0..200 {
+        entry = std::fs::read_dir(&dead)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .find(|x| x.extension().is_some_and(|x| x == "json"));
+        if entry.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let entry = entry.expect("message was not moved to dead letters");
+    let contents = std::fs::read_to_string(&entry).unwrap();
+    assert!(contents.contains("\"attempts\":3"), "{}", contents);
+    assert!(
+        contents.contains("451 4.3.0 try again later"),
+        "{}",
+        contents
+    );
+    assert_eq!(std::fs::read_dir(&dead).unwrap().count(), 2);
+
+    let mut attempts = Vec::new();
+    for _ in 0..100 {
+        attempts = sink.attempts.lock().unwrap().clone();
+        if attempts.len() >= 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    // The bounce is only queued once the message has been given up on.
+    assert!(attempts.len() >= 4, "{}", attempts.len());
+    for (_, body) in &attempts[..3] {
+        assert!(String::from_utf8_lossy(body).starts_with("From: Sender <alice@example.com>"));
+    }
+    for pair in attempts[..3].windows(2) {
+        assert!(pair[1].0 - pair[0].0 >= Duration::from_millis(900));
+    }
+    assert!(String::from_utf8_lossy(&attempts[3].1).contains("Status: 5.4.7"));
+}
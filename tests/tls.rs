@@ -0,0 +1,318 @@
+mod common;
+
+use common::{mail_config, MemorySink};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use smtp2larkapi::smtp_server::{plain_encode, serve, Account, MailConfig, TlsType};
+use smtp2larkapi::tls::{server_config, KeyPair, TlsConfig};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Accepts any certificate, so the test can look at which one the server picked.
+#[derive(Debug)]
+struct AcceptAny(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smtp2larkapi-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a self-signed certificate for `name` and returns its paths and DER encoding.
+fn write_cert(dir: &Path, stem: &str, name: &str) -> (KeyPair, Vec<u8>) {
+    let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    let cert = dir.join(format!("{}.crt", stem));
+    let key = dir.join(format!("{}.key", stem));
+    std::fs::write(&cert, generated.cert.pem()).unwrap();
+    std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+    let pair = KeyPair {
+        cert: cert.to_str().unwrap().to_string(),
+        key: key.to_str().unwrap().to_string(),
+    };
+    (pair, generated.cert.der().to_vec())
+}
+
+fn client_config(
+    client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> rustls::ClientConfig {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAny(provider)));
+    match client_cert {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key).unwrap(),
+        None => builder.with_no_client_auth(),
+    }
+}
+
+/// Completes a handshake asking for `server_name` and returns the certificate the server sent.
+async fn served_cert(config: Arc<rustls::ServerConfig>, server_name: &str) -> Vec<u8> {
+    let client_config = client_config(None);
+    let (client, server) = tokio::io::duplex(1 << 16);
+    let server = tokio::spawn(async move { TlsAcceptor::from(config).accept(server).await });
+    let client = TlsConnector::from(Arc::new(client_config))
+        .connect(
+            ServerName::try_from(server_name.to_string()).unwrap(),
+            client,
+        )
+        .await
+        .unwrap();
+    server.await.unwrap().unwrap();
+    client.get_ref().1.peer_certificates().unwrap()[0].to_vec()
+}
+
+#[tokio::test]
+async fn picks_certificate_by_sni_name() {
+    let dir = temp_dir("sni");
+    let (default, default_der) = write_cert(&dir, "default", "mail.test");
+    let (exact, exact_der) = write_cert(&dir, "exact", "smtp.example.com");
+    let (wildcard, wildcard_der) = write_cert(&dir, "wildcard", "*.example.org");
+    let mut sni = BTreeMap::new();
+    sni.insert("SMTP.example.com".to_string(), exact);
+    sni.insert("*.example.org".to_string(), wildcard);
+    let (config, _) = server_config(TlsConfig {
+        default,
+        sni,
+        client_ca: None,
+    })
+    .unwrap();
+
+    assert_eq!(
+        served_cert(config.clone(), "smtp.example.com").await,
+        exact_der
+    );
+    assert_eq!(
+        served_cert(config.clone(), "mx.example.org").await,
+        wildcard_der
+    );
+    assert_eq!(
+        served_cert(config.clone(), "a.mx.example.org").await,
+        default_der
+    );
+    assert_eq!(served_cert(config, "other.test").await, default_der);
+}
+
+#[tokio::test]
+async fn reloads_rotated_certificate_and_keeps_it_on_bad_files() {
+    let dir = temp_dir("reload");
+    let (pair, first) = write_cert(&dir, "mail", "mail.test");
+    let (config, resolver) = server_config(TlsConfig {
+        default: pair.clone(),
+        sni: BTreeMap::new(),
+        client_ca: None,
+    })
+    .unwrap();
+    assert_eq!(served_cert(config.clone(), "mail.test").await, first);
+    assert!(!resolver.changed());
+
+    let (_, second) = write_cert(&dir, "mail", "mail.test");
+    assert!(resolver.changed());
+    resolver.reload().unwrap();
+    assert!(!resolver.changed());
+    assert_eq!(served_cert(config.clone(), "mail.test").await, second);
+
+    // A key that does not belong to the certificate is rejected.
+    write_cert(&dir, "other", "mail.test");
+    std::fs::copy(dir.join("other.key"), &pair.key).unwrap();
+    let error = resolver.reload().unwrap_err().to_string();
+    assert!(error.contains("does not match"), "{}", error);
+    std::fs::write(&pair.cert, "not a certificate").unwrap();
+    let error = resolver.reload().unwrap_err().to_string();
+    assert!(error.contains(&pair.cert), "{}", error);
+    assert_eq!(served_cert(config, "mail.test").await, second);
+}
+
+#[test]
+fn reports_unreadable_files_instead_of_panicking() {
+    let dir = temp_dir("unreadable");
+    let missing = KeyPair {
+        cert: dir.join("missing.crt").to_str().unwrap().to_string(),
+        key: dir.join("missing.key").to_str().unwrap().to_string(),
+    };
+    let error = server_config(TlsConfig {
+        default: missing.clone(),
+        sni: BTreeMap::new(),
+        client_ca: None,
+    })
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains(&missing.cert), "{}", error);
+}
+
+/// A client certificate for `name` issued by `issuer`, or self-signed without one.
+fn client_cert(
+    name: &str,
+    issuer: Option<(&rcgen::Certificate, &rcgen::KeyPair)>,
+) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+    let key = rcgen::KeyPair::generate().unwrap();
+    let mut params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+    params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+    let cert = match issuer {
+        Some((issuer, issuer_key)) => params.signed_by(&key, issuer, issuer_key).unwrap(),
+        None => params.self_signed(&key).unwrap(),
+    };
+    let der = PrivatePkcs8KeyDer::from(key.serialize_der());
+    (vec![cert.der().clone()], der.into())
+}
+
+/// Runs `script` through an implicit-TLS SMTP session and returns everything the server wrote.
+async fn smtp_over_tls(
+    config: Arc<MailConfig>,
+    client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    script: &str,
+) -> (String, Arc<MemorySink>) {
+    let sink = Arc::new(MemorySink::default());
+    let (client, server) = tokio::io::duplex(1 << 16);
+    tokio::spawn(serve(server, config, sink.clone()));
+    let mut output = Vec::new();
+    let connector = TlsConnector::from(Arc::new(client_config(client_cert)));
+    if let Ok(mut client) = connector
+        .connect(ServerName::try_from("mail.test").unwrap(), client)
+        .await
+    {
+        if client.write_all(script.as_bytes()).await.is_ok() {
+            // The server closes without close_notify after QUIT, which shows up as an error here.
+            let _ = tokio::time::timeout(
+                std::time::Duration::from_secs(10),
+                client.read_to_end(&mut output),
+            )
+            .await
+            .unwrap();
+        }
+    }
+    (String::from_utf8_lossy(&output).to_string(), sink)
+}
+
+#[tokio::test]
+async fn client_certificate_logs_in_as_mapped_account() {
+    let dir = temp_dir("mtls");
+    let (default, _) = write_cert(&dir, "server", "mail.test");
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "Relay CA");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let ca_path = dir.join("ca.crt");
+    std::fs::write(&ca_path, ca.pem()).unwrap();
+
+    let (tls_cert, _) = server_config(TlsConfig {
+        default,
+        sni: BTreeMap::new(),
+        client_ca: Some(ca_path.to_str().unwrap().to_string()),
+    })
+    .unwrap();
+    let mut config = (*mail_config()).clone();
+    config.accounts.push(Account {
+        user: "printer".to_string(),
+        senders: Some(vec!["printer@example.com".to_string()]),
+        client_cert_names: vec!["printer.internal".to_string()],
+        ..Default::default()
+    });
+    // Certificate-only accounts cannot log in with an empty password.
+    let output = common::smtp_session(
+        Arc::new(config.clone()),
+        Arc::new(MemorySink::default()),
+        format!("AUTH PLAIN {}\r\n", plain_encode("printer", "")),
+    )
+    .await;
+    assert!(output.contains("535 5.7.8 "), "{}", output);
+    config.tls_type = Some(TlsType::SSL);
+    config.tls_cert = Some(tls_cert);
+    let config = Arc::new(config);
+
+    let script = format!(
+        "EHLO client.test\r\n\
+         MAIL FROM:<relay@example.com>\r\n\
+         MAIL FROM:<printer@example.com>\r\n\
+         RCPT TO:<bob@example.com>\r\n\
+         DATA\r\n\
+         Subject: scan\r\n\r\nscan\r\n.\r\n\
+         AUTH PLAIN {}\r\n\
+         QUIT\r\n",
+        plain_encode("relay", "secret")
+    );
+    let cert = client_cert("printer.internal", Some((&ca, &ca_key)));
+    let (output, sink) = smtp_over_tls(config.clone(), Some(cert), &script).await;
+    assert!(
+        output.contains("550 5.7.1 Sender address rejected: not owned by user printer\r\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("250 2.0.0 OK\r\n"), "{}", output);
+    assert!(
+        output.contains("503 5.5.1 Already authenticated\r\n"),
+        "{}",
+        output
+    );
+    assert_eq!(sink.take().len(), 1);
+
+    // Without a certificate the session has to AUTH as before.
+    let (output, _) = smtp_over_tls(config.clone(), None, &script).await;
+    assert!(
+        output.contains("530 5.7.0 Authentication required\r\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("235 2.7.0"), "{}", output);
+
+    // A certificate from another CA fails the handshake.
+    let cert = client_cert("printer.internal", None);
+    let (output, sink) = smtp_over_tls(config, Some(cert), &script).await;
+    assert!(!output.contains("250 "), "{}", output);
+    assert!(sink.take().is_empty());
+}